@@ -1,8 +1,11 @@
 use crate::error::{Error, Result};
 use crate::host::Host;
+use glob::{Pattern, glob};
 use normpath::PathExt;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CargoMetadata {
@@ -18,6 +21,11 @@ pub struct CargoCrate {
     pub targets: Vec<CargoTarget>,
     pub manifest_path: PathBuf,
     pub dependencies: Vec<CargoDependency>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// The `[package.metadata]` table.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +39,76 @@ pub struct CargoTarget {
 pub struct CargoDependency {
     pub name: String,
     pub source: Option<String>,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default)]
+    pub rename: Option<String>,
+}
+
+impl CargoCrate {
+    /// Environment variables cargo provides when compiling this crate, as far as they are known
+    /// without building it.
+    pub fn env_vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        if let Some(manifest_dir) = self.manifest_path.parent() {
+            let _ = vars.insert("CARGO_MANIFEST_DIR".to_string(), manifest_dir.to_string_lossy().to_string());
+        }
+        let _ = vars.insert("CARGO_MANIFEST_PATH".to_string(), self.manifest_path.to_string_lossy().to_string());
+        let _ = vars.insert("CARGO_PKG_NAME".to_string(), self.name.clone());
+        let _ = vars.insert("CARGO_CRATE_NAME".to_string(), self.name.replace('-', "_"));
+        vars
+    }
+
+    /// Source of the `custom-build` target, if the crate has a build script.
+    pub fn build_script(&self) -> Option<&Path> {
+        self.targets
+            .iter()
+            .find(|target| target.kind.iter().any(|kind| kind == "custom-build"))
+            .map(|target| target.src_path.as_path())
+    }
+}
+
+/// Paths that the build script of `package` emitted as `rerun-if-changed`, read from the
+/// `build/<package>-<hash>/output` files of every profile (and target triple) in `target_dir`.
+pub fn build_script_rerun_paths(target_dir: &Path, package: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+
+    for build_dir in ["*/build", "*/*/build"] {
+        let pattern = target_dir
+            .join(build_dir)
+            .join(format!("{}-*", Pattern::escape(package)))
+            .join("output");
+        let Ok(outputs) = glob(&pattern.to_string_lossy()) else {
+            continue;
+        };
+
+        for output in outputs.flatten() {
+            // `foo-*` also matches the build directories of `foo-bar`.
+            let is_own_hash = output
+                .parent()
+                .and_then(|dir| dir.file_name()?.to_str()?.strip_prefix(package)?.strip_prefix('-'))
+                .is_some_and(|hash| hash.chars().all(|c| c.is_ascii_hexdigit()));
+
+            if is_own_hash && let Ok(content) = fs::read_to_string(&output) {
+                paths.extend(content.lines().filter_map(|line| {
+                    line.strip_prefix("cargo:rerun-if-changed=")
+                        .or_else(|| line.strip_prefix("cargo::rerun-if-changed="))
+                        .map(ToString::to_string)
+                }));
+            }
+        }
+    }
+
+    paths
+}
+
+/// The value at `keys` of the manifest at `manifest_path`, e.g. `["profile"]`.
+pub fn manifest_value(manifest_path: &Path, keys: &[&str]) -> Option<serde_json::Value> {
+    let content = fs::read_to_string(manifest_path).ok()?;
+    let manifest = toml::Value::Table(toml::from_str(&content).ok()?);
+    let value = keys.iter().try_fold(&manifest, |value, key| value.get(key))?;
+
+    serde_json::to_value(value).ok()
 }
 
 /// Get cargo metadata from current working directory
@@ -114,6 +192,85 @@ mod tests {
         assert!(result.unwrap_err().to_string().contains("cargo not installed"));
     }
 
+    #[test]
+    fn env_vars_describe_crate() {
+        let crate_ = CargoCrate {
+            name: "my-crate".to_string(),
+            source: None,
+            targets: vec![],
+            manifest_path: PathBuf::from("/ws/my-crate/Cargo.toml"),
+            dependencies: vec![],
+            features: BTreeMap::new(),
+            metadata: None,
+        };
+
+        let vars = crate_.env_vars();
+        assert_eq!(vars["CARGO_MANIFEST_DIR"], PathBuf::from("/ws/my-crate").to_string_lossy());
+        assert_eq!(vars["CARGO_PKG_NAME"], "my-crate");
+        assert_eq!(vars["CARGO_CRATE_NAME"], "my_crate");
+    }
+
+    #[test]
+    fn build_script_finds_custom_build_target() {
+        let target = |kind: &str, src_path: &str| CargoTarget {
+            name: "t".to_string(),
+            kind: vec![kind.to_string()],
+            src_path: PathBuf::from(src_path),
+        };
+        let mut crate_ = CargoCrate {
+            name: "my-crate".to_string(),
+            source: None,
+            targets: vec![target("lib", "src/lib.rs")],
+            manifest_path: PathBuf::from("Cargo.toml"),
+            dependencies: vec![],
+            features: BTreeMap::new(),
+            metadata: None,
+        };
+        assert!(crate_.build_script().is_none());
+
+        crate_.targets.push(target("custom-build", "build.rs"));
+        assert_eq!(crate_.build_script(), Some(Path::new("build.rs")));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn build_script_rerun_paths_reads_output_files() {
+        let target = std::env::temp_dir().join("cargo_delta_test_build_output");
+        let _ = fs::remove_dir_all(&target);
+        let own = target.join("debug/build/my-crate-0123456789abcdef");
+        let other = target.join("debug/build/my-crate-ext-0123456789abcdef");
+        let cross = target.join("x86_64-unknown-linux-gnu/release/build/my-crate-fedcba9876543210");
+        for dir in [&own, &other, &cross] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(own.join("output"), "cargo:rerun-if-changed=proto/a.proto\ncargo:rustc-cfg=foo\n").unwrap();
+        fs::write(other.join("output"), "cargo:rerun-if-changed=other.h\n").unwrap();
+        fs::write(cross.join("output"), "cargo::rerun-if-changed=native/lib.c\n").unwrap();
+
+        let mut paths = build_script_rerun_paths(&target, "my-crate");
+        let _ = fs::remove_dir_all(&target);
+
+        paths.sort();
+        assert_eq!(paths, vec!["native/lib.c", "proto/a.proto"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn manifest_value_reads_nested_tables() {
+        let root = std::env::temp_dir().join("cargo_delta_test_manifest_value");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let manifest = root.join("Cargo.toml");
+        fs::write(&manifest, "[workspace]\nmembers = []\n\n[profile.release]\nlto = true\n").unwrap();
+
+        let profile = manifest_value(&manifest, &["profile"]);
+        let metadata = manifest_value(&manifest, &["workspace", "metadata", "delta"]);
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(profile, Some(serde_json::json!({ "release": { "lto": true } })));
+        assert_eq!(metadata, None);
+    }
+
     #[test]
     fn get_workspace_crates_filters_external_packages() {
         let meta = CargoMetadata {
@@ -124,6 +281,8 @@ mod tests {
                     targets: vec![],
                     manifest_path: PathBuf::from("Cargo.toml"),
                     dependencies: vec![],
+                    features: BTreeMap::new(),
+                    metadata: None,
                 },
                 CargoCrate {
                     name: "external".to_string(),
@@ -131,6 +290,8 @@ mod tests {
                     targets: vec![],
                     manifest_path: PathBuf::from("Cargo.toml"),
                     dependencies: vec![],
+                    features: BTreeMap::new(),
+                    metadata: None,
                 },
             ],
             workspace_root: PathBuf::from("."),
@@ -1,31 +1,452 @@
-use serde::{Deserialize, Serialize};
+use glob::Pattern;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeSeed, Error as _, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
 };
 
+use crate::cargo::CargoCrate;
 use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct MainConfig {
     #[serde(default)]
-    pub parser: ParserConfig,
+    pub parser: ParserSection,
     #[serde(default)]
     pub git: Option<GitConfig>,
-    #[serde(default = "default_file_excludes")]
+    #[serde(default = "default_file_excludes", deserialize_with = "globs")]
     pub file_exclude_patterns: Vec<String>,
+    #[serde(default, deserialize_with = "trip_wires", serialize_with = "serialize_trip_wires")]
+    pub trip_wire_patterns: Vec<TripWire>,
+    /// Built-in trip wires, each on unless turned off.
     #[serde(default)]
-    pub trip_wire_patterns: Vec<String>,
+    pub default_trip_wires: DefaultTripWires,
+    /// Crates, by name or glob, left out of every run result.
+    #[serde(default, deserialize_with = "globs")]
+    pub ignore_crates: Vec<String>,
+    /// Crates, by name or glob, affected by every change.
+    #[serde(default, deserialize_with = "globs")]
+    pub always_crates: Vec<String>,
+    /// Named groups of crates, by name or glob, affected together when any member is affected.
+    #[serde(default, deserialize_with = "crate_groups")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Config files (relative to this one) and `preset:<name>` presets merged below this config.
+    #[serde(default)]
+    pub extends: Vec<String>,
+    /// Files assigned to crates by `[[map]]` rules.
+    #[serde(default)]
+    pub map: Vec<MapRule>,
+    /// Parser overrides read from `[package.metadata.delta]` of each crate.
+    #[serde(skip)]
+    pub package_configs: HashMap<String, toml::Table>,
+    /// Layer that set each configured value, keyed by its path of table keys.
+    #[serde(skip)]
+    pub origins: BTreeMap<Vec<String>, String>,
+    /// Config files read: the source file, unless it is the workspace manifest, and its `extends` bases.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+/// Built-in trip wires for files that change how every crate is built or checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[expect(clippy::struct_excessive_bools, reason = "configuration struct mirrors TOML schema")]
+pub struct DefaultTripWires {
+    /// `rust-toolchain.toml` and `rust-toolchain`.
+    pub toolchain: bool,
+    /// `.cargo/config.toml` and `.cargo/config`.
+    pub cargo_config: bool,
+    /// `clippy.toml` and `.clippy.toml`.
+    pub clippy: bool,
+    /// `rustfmt.toml` and `.rustfmt.toml`.
+    pub rustfmt: bool,
+    /// The `[profile]` section of the workspace `Cargo.toml`.
+    pub profile: bool,
+    /// The delta config, its `extends` bases and `[workspace.metadata.delta]`.
+    pub config: bool,
+}
+
+impl Default for DefaultTripWires {
+    fn default() -> Self {
+        Self {
+            toolchain: true,
+            cargo_config: true,
+            clippy: true,
+            rustfmt: true,
+            profile: true,
+            config: true,
+        }
+    }
+}
+
+impl DefaultTripWires {
+    /// Why a change to `file` trips an enabled built-in trip wire matched by name. Paths are relative
+    /// to the git root: the configs count in `workspace_dir` only, `.cargo/` also in its ancestors.
+    pub fn reason(self, file: &Path, workspace_dir: &Path) -> Option<&'static str> {
+        let name = file.file_name()?.to_str()?;
+        let dir = file.parent()?;
+        let in_workspace_dir = dir == workspace_dir;
+        let in_cargo_dir =
+            dir.file_name().is_some_and(|name| name == ".cargo") && dir.parent().is_some_and(|parent| workspace_dir.starts_with(parent));
+
+        match name {
+            "rust-toolchain.toml" | "rust-toolchain" if self.toolchain && in_workspace_dir => Some("Rust toolchain"),
+            "config.toml" | "config" if self.cargo_config && in_cargo_dir => Some("Cargo configuration"),
+            "clippy.toml" | ".clippy.toml" if self.clippy && in_workspace_dir => Some("Clippy configuration"),
+            "rustfmt.toml" | ".rustfmt.toml" if self.rustfmt && in_workspace_dir => Some("rustfmt configuration"),
+            _ => None,
+        }
+    }
+}
+
+/// Files matching `pattern`, relative to the git root, belong to `crates`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MapRule {
+    #[serde(deserialize_with = "glob")]
+    pub pattern: String,
+    pub crates: Vec<String>,
+}
+
+impl MapRule {
+    /// Whether `file`, relative to the git root, matches the rule.
+    pub fn matches(&self, file: &Path) -> bool {
+        Pattern::new(&self.pattern).is_ok_and(|pattern| pattern.matches_path(file))
+    }
+}
+
+/// Changes to files matching `pattern` impact the crates matching `crates` globs, and
+/// `dependents_of` with every crate depending on it. A plain pattern impacts the whole workspace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TripWire {
+    #[serde(deserialize_with = "glob")]
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", deserialize_with = "globs")]
+    pub crates: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependents_of: Option<String>,
+}
+
+impl TripWire {
+    /// Whether the trip wire impacts only some crates.
+    pub const fn is_scoped(&self) -> bool {
+        !self.crates.is_empty() || self.dependents_of.is_some()
+    }
+
+    /// Whether `file`, relative to the git root, matches the trip wire.
+    pub fn matches(&self, file: &Path) -> bool {
+        Pattern::new(&self.pattern).is_ok_and(|pattern| pattern.matches(&file.to_string_lossy()))
+    }
+}
+
+impl From<&str> for TripWire {
+    fn from(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            crates: Vec::new(),
+            dependents_of: None,
+        }
+    }
+}
+
+impl fmt::Display for TripWire {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)?;
+        let mut scopes = Vec::new();
+        if !self.crates.is_empty() {
+            scopes.push(format!("crates {}", self.crates.join(", ")));
+        }
+        if let Some(crate_name) = &self.dependents_of {
+            scopes.push(format!("dependents of {crate_name}"));
+        }
+        if !scopes.is_empty() {
+            write!(f, " ({})", scopes.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes trip wires, each a plain pattern or a table.
+fn trip_wires<'de, D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Vec<TripWire>, D::Error> {
+    struct TripWiresVisitor;
+
+    impl<'de> Visitor<'de> for TripWiresVisitor {
+        type Value = Vec<TripWire>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("a list of trip wires")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> core::result::Result<Self::Value, A::Error> {
+            let mut wires = Vec::new();
+            while let Some(wire) = seq.next_element_seed(TripWireEntry)? {
+                wires.push(wire);
+            }
+            Ok(wires)
+        }
+    }
+
+    deserializer.deserialize_seq(TripWiresVisitor)
+}
+
+/// A trip wire written as a plain pattern or as a table.
+struct TripWireEntry;
+
+impl<'de> DeserializeSeed<'de> for TripWireEntry {
+    type Value = TripWire;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> core::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for TripWireEntry {
+    type Value = TripWire;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a glob pattern or a table with `pattern`, `crates` and `dependents_of`")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> core::result::Result<Self::Value, E> {
+        match Pattern::new(v) {
+            Ok(_) => Ok(TripWire::from(v)),
+            Err(e) => Err(E::custom(format!("invalid glob `{v}`: {e}"))),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> core::result::Result<Self::Value, A::Error> {
+        TripWire::deserialize(MapAccessDeserializer::new(map))
+    }
+}
+
+/// Serializes unscoped trip wires as plain patterns.
+fn serialize_trip_wires<S: Serializer>(wires: &[TripWire], serializer: S) -> core::result::Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(wires.len()))?;
+    for wire in wires {
+        if wire.is_scoped() {
+            seq.serialize_element(wire)?;
+        } else {
+            seq.serialize_element(&wire.pattern)?;
+        }
+    }
+    seq.end()
+}
+
+/// A resolved setting and the layer that set it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    /// Table keys leading to the setting, e.g. `["git", "remote_branch"]`.
+    pub path: Vec<String>,
+    pub value: toml::Value,
+    pub origin: String,
+}
+
+/// The `[parser]` table: global settings, followed by `[parser.<crate>]` overrides keyed by crate
+/// name or glob. Overrides only hold the fields they change.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ParserSection {
+    #[serde(flatten)]
+    pub global: ParserConfig,
     #[serde(flatten)]
-    pub crate_configs: HashMap<String, ParserConfig>,
+    pub overrides: BTreeMap<String, toml::Table>,
+}
+
+impl<'de> Deserialize<'de> for ParserSection {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(ParserSectionVisitor)
+    }
+}
+
+struct ParserSectionVisitor;
+
+impl<'de> Visitor<'de> for ParserSectionVisitor {
+    type Value = ParserSection;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a table of parser settings and crate overrides")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> core::result::Result<Self::Value, A::Error> {
+        let mut global = toml::Table::new();
+        let mut overrides = BTreeMap::new();
+
+        // Entries are checked one by one, so errors point at the offending entry.
+        while let Some(key) = map.next_key::<String>()? {
+            // Parser settings are never tables, so every table is a crate override.
+            match map.next_value_seed(ParserEntry { key: &key })? {
+                toml::Value::Table(layer) => {
+                    let _ = overrides.insert(key, layer);
+                }
+                value => {
+                    let _ = global.insert(key, value);
+                }
+            }
+        }
+
+        let global = apply_layers(&ParserConfig::default(), &[&global]).map_err(|e| A::Error::custom(e.message()))?;
+
+        Ok(ParserSection { global, overrides })
+    }
+}
+
+/// A `[parser]` entry, checked against the parser settings.
+struct ParserEntry<'a> {
+    key: &'a str,
+}
+
+impl<'de> DeserializeSeed<'de> for ParserEntry<'_> {
+    type Value = toml::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> core::result::Result<Self::Value, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+
+        if let toml::Value::Table(layer) = &value {
+            if let Err(e) = Pattern::new(self.key) {
+                return Err(D::Error::custom(format!("invalid crate glob in [parser.{}]: {e}", self.key)));
+            }
+            for (key, value) in layer {
+                if value.is_table() {
+                    return Err(D::Error::custom(format!("unexpected table `{key}` in [parser.{}]", self.key)));
+                }
+                check_setting(key, value).map_err(|e| D::Error::custom(format!("in [parser.{}]: {e}", self.key)))?;
+            }
+        } else {
+            check_setting(self.key, &value).map_err(D::Error::custom)?;
+        }
+
+        Ok(value)
+    }
+}
+
+/// Checks a single parser setting, or an `extend_`/`remove_` operator on a list setting.
+fn check_setting(key: &str, value: &toml::Value) -> core::result::Result<(), String> {
+    let field = match key.strip_prefix("extend_").or_else(|| key.strip_prefix("remove_")) {
+        Some(field) => {
+            let defaults = toml::Table::try_from(ParserConfig::default()).map_err(|e| e.to_string())?;
+            if !defaults.get(field).is_some_and(toml::Value::is_array) {
+                return Err(format!("`{key}` does not name a list setting"));
+            }
+            field
+        }
+        None => key,
+    };
+
+    let mut table = toml::Table::new();
+    let _ = table.insert(field.to_string(), value.clone());
+    toml::Value::Table(table)
+        .try_into::<ParserConfig>()
+        .map(|_| ())
+        .map_err(|e| e.message().to_string())
+}
+
+/// Deserializes a glob pattern, rejecting an invalid one.
+fn glob<'de, D: Deserializer<'de>>(deserializer: D) -> core::result::Result<String, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+
+    match Pattern::new(&pattern) {
+        Ok(_) => Ok(pattern),
+        Err(e) => Err(D::Error::custom(format!("invalid glob `{pattern}`: {e}"))),
+    }
+}
+
+/// Deserializes a list of glob patterns, rejecting invalid ones.
+fn globs<'de, D, C>(deserializer: D) -> core::result::Result<C, D::Error>
+where
+    D: Deserializer<'de>,
+    C: FromIterator<String>,
+{
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+
+    for pattern in &patterns {
+        if let Err(e) = Pattern::new(pattern) {
+            return Err(D::Error::custom(format!("invalid glob `{pattern}`: {e}")));
+        }
+    }
+
+    Ok(patterns.into_iter().collect())
+}
+
+/// Deserializes `[groups]`, rejecting invalid crate globs.
+fn crate_groups<'de, D: Deserializer<'de>>(deserializer: D) -> core::result::Result<BTreeMap<String, Vec<String>>, D::Error> {
+    let groups = BTreeMap::<String, Vec<String>>::deserialize(deserializer)?;
+
+    for (group, patterns) in &groups {
+        for pattern in patterns {
+            if let Err(e) = Pattern::new(pattern) {
+                return Err(D::Error::custom(format!("invalid glob `{pattern}` in group `{group}`: {e}")));
+            }
+        }
+    }
+
+    Ok(groups)
+}
+
+/// Names in `crate_names` matching any of `patterns`, by name or glob.
+pub fn select_crates(patterns: &[String], crate_names: &[String]) -> BTreeSet<String> {
+    let patterns: Vec<Pattern> = patterns.iter().filter_map(|pattern| Pattern::new(pattern).ok()).collect();
+
+    crate_names
+        .iter()
+        .filter(|name| patterns.iter().any(|pattern| pattern.matches(name)))
+        .cloned()
+        .collect()
+}
+
+impl ParserSection {
+    /// Overrides matching `crate_name`: glob matches in key order, then the exact name.
+    fn matching<'a>(&'a self, crate_name: &'a str) -> impl Iterator<Item = (&'a String, &'a toml::Table)> {
+        let globs = self
+            .overrides
+            .iter()
+            .filter(move |(key, _)| *key != crate_name && Pattern::new(key).is_ok_and(|pattern| pattern.matches(crate_name)));
+
+        globs.chain(self.overrides.get_key_value(crate_name))
+    }
+
+    fn layers_for<'a>(&'a self, crate_name: &'a str) -> impl Iterator<Item = &'a toml::Table> {
+        self.matching(crate_name).map(|(_, layer)| layer)
+    }
+}
+
+/// Where the configuration was loaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// A `delta.toml` style file.
+    File(PathBuf),
+    /// The `[workspace.metadata.delta]` table of the given root manifest.
+    WorkspaceMetadata(PathBuf),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::WorkspaceMetadata(manifest) => write!(f, "{} [workspace.metadata.delta]", manifest.display()),
+        }
+    }
+}
+
+/// Crate-level configuration in `[package.metadata.delta]`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackageConfig {
+    parser: Option<toml::Table>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitConfig {
     pub remote_branch: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 #[expect(clippy::struct_excessive_bools, reason = "configuration struct mirrors TOML schema")]
 pub struct ParserConfig {
     #[serde(default = "default_true")]
@@ -36,16 +457,61 @@ pub struct ParserConfig {
     pub includes: bool,
     #[serde(default = "default_include_macros")]
     pub include_macros: HashSet<String>,
+    #[serde(default = "default_source_include_macros")]
+    pub source_include_macros: HashSet<String>,
     #[serde(default = "default_true")]
     pub mods: bool,
     #[serde(default = "default_mod_macros")]
     pub mod_macros: HashSet<String>,
+    #[serde(default = "default_true")]
+    pub dir_refs: bool,
+    #[serde(default = "default_dir_macros")]
+    pub dir_macros: HashSet<String>,
+    #[serde(default = "default_dir_methods")]
+    pub dir_methods: HashSet<String>,
+    #[serde(default = "default_true")]
+    pub macro_bodies: bool,
+    #[serde(default = "default_true")]
+    pub attributes: bool,
+    #[serde(default)]
+    pub attribute_refs: Vec<AttributeRef>,
+    #[serde(default = "default_true")]
+    pub build_inputs: bool,
+    #[serde(default = "default_build_methods")]
+    pub build_methods: HashSet<String>,
     #[serde(default = "default_false")]
     pub assume: bool,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "globs")]
     pub assume_patterns: HashSet<String>,
 }
 
+/// File or directory referenced by an attribute, e.g. `#[template(path = "index.html")]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeRef {
+    /// Attribute path, e.g. `template` or `serde_with::file`.
+    pub attribute: String,
+    /// Key inside the attribute arguments. Without a key, the attribute value itself is used, as in `#[folder = "static/"]`.
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub relative_to: RelativeTo,
+    /// Directory prepended to the value, e.g. `templates` for askama.
+    #[serde(default)]
+    pub base: Option<String>,
+    /// Whether the value names a directory that claims every file below it.
+    #[serde(default)]
+    pub directory: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelativeTo {
+    #[default]
+    Crate,
+    File,
+}
+
 impl Default for ParserConfig {
     fn default() -> Self {
         // Use serde's deserialization to get the defaults.
@@ -66,7 +532,7 @@ const fn default_false() -> bool {
 }
 
 fn default_file_methods() -> HashSet<String> {
-    ["file", "from_file", "load", "open", "read", "load_from"]
+    ["file", "from_file", "load", "open", "read", "load_from", "read_to_string", "join"]
         .iter()
         .map(|s| (*s).to_string())
         .collect()
@@ -76,6 +542,22 @@ fn default_include_macros() -> HashSet<String> {
     ["include_str", "include_bytes"].iter().map(|s| (*s).to_string()).collect()
 }
 
+fn default_source_include_macros() -> HashSet<String> {
+    HashSet::from(["include".to_string()])
+}
+
+fn default_dir_macros() -> HashSet<String> {
+    ["include_dir", "migrate"].iter().map(|s| (*s).to_string()).collect()
+}
+
+fn default_dir_methods() -> HashSet<String> {
+    HashSet::from(["read_dir".to_string()])
+}
+
+fn default_build_methods() -> HashSet<String> {
+    ["file", "files", "compile_protos"].iter().map(|s| (*s).to_string()).collect()
+}
+
 fn default_mod_macros() -> HashSet<String> {
     HashSet::new()
 }
@@ -88,23 +570,650 @@ impl Default for MainConfig {
 }
 
 impl MainConfig {
+    /// Parser settings of `crate_name`: the global `[parser]` table, then matching `[parser.<glob>]`
+    /// and `[parser.<crate>]` overrides, then `[package.metadata.delta.parser]` of the crate.
     pub fn crate_config(&self, crate_name: &str) -> ParserConfig {
-        let crate_key = format!("parser.{crate_name}");
-        self.crate_configs.get(&crate_key).cloned().unwrap_or_else(|| self.parser.clone())
+        let layers: Vec<_> = self
+            .parser
+            .layers_for(crate_name)
+            .chain(self.package_configs.get(crate_name))
+            .collect();
+
+        // Every layer was applied once when it was loaded, so this only fails on a broken invariant.
+        apply_layers(&self.parser.global, &layers).unwrap_or_else(|_| self.parser.global.clone())
+    }
+
+    /// Every resolved setting with its origin. With a crate, `[parser]` holds the settings of that
+    /// crate; without, the global settings followed by the `[parser.<crate>]` overrides.
+    pub fn settings(&self, crate_name: Option<&str>) -> Vec<Setting> {
+        let mut settings = Vec::new();
+
+        if let Ok(mut table) = toml::Table::try_from(self) {
+            let _ = table.remove("parser");
+            self.add_settings(&mut settings, &[], table);
+        }
+
+        let parser = crate_name.map_or_else(|| self.parser.global.clone(), |name| self.crate_config(name));
+        for (field, value) in toml::Table::try_from(parser).unwrap_or_default() {
+            let origin = self.parser_origin(&field, crate_name);
+            settings.push(Setting {
+                path: vec!["parser".to_string(), field],
+                value: sorted(value),
+                origin,
+            });
+        }
+
+        if crate_name.is_none() {
+            for (key, layer) in &self.parser.overrides {
+                self.add_settings(&mut settings, &["parser".to_string(), key.clone()], layer.clone());
+            }
+        }
+
+        settings
+    }
+
+    fn add_settings(&self, settings: &mut Vec<Setting>, prefix: &[String], table: toml::Table) {
+        // Values come before nested tables, as they would in a TOML file.
+        let (tables, values): (Vec<_>, Vec<_>) = table.into_iter().partition(|(_, value)| value.is_table());
+
+        for (key, value) in values {
+            let path = [prefix, &[key]].concat();
+            let origin = self.origin(&path);
+            settings.push(Setting {
+                path,
+                value: sorted(value),
+                origin,
+            });
+        }
+
+        for (key, value) in tables {
+            if let toml::Value::Table(table) = value {
+                self.add_settings(settings, &[prefix, &[key]].concat(), table);
+            }
+        }
+    }
+
+    fn origin(&self, path: &[String]) -> String {
+        self.origins.get(path).cloned().unwrap_or_else(|| "default".to_string())
+    }
+
+    /// The last layer that set, extended or trimmed the parser setting `field` of `crate_name`.
+    fn parser_origin(&self, field: &str, crate_name: Option<&str>) -> String {
+        let keys = [field.to_string(), format!("extend_{field}"), format!("remove_{field}")];
+        let touched = |layer: &toml::Table| keys.iter().find(|key| layer.contains_key(*key));
+
+        let mut origin = keys
+            .iter()
+            .find_map(|key| self.origins.get(&["parser".to_string(), key.clone()][..]))
+            .cloned()
+            .unwrap_or_else(|| "default".to_string());
+
+        let Some(crate_name) = crate_name else {
+            return origin;
+        };
+
+        for (override_key, layer) in self.parser.matching(crate_name) {
+            if let Some(key) = touched(layer) {
+                let file = self.origin(&["parser".to_string(), override_key.clone(), key.clone()]);
+                origin = format!("[parser.{}] in {file}", toml_key(override_key));
+            }
+        }
+
+        if self.package_configs.get(crate_name).and_then(touched).is_some() {
+            origin = format!("[package.metadata.delta] of {crate_name}");
+        }
+
+        origin
+    }
+
+    /// Records `origin` for every value in `table`, a layer of the configuration.
+    fn record_origins(&mut self, prefix: &[String], table: &toml::Table, origin: &str) {
+        for (key, value) in table {
+            let path = [prefix, core::slice::from_ref(key)].concat();
+            if let toml::Value::Table(table) = value {
+                self.record_origins(&path, table, origin);
+            } else {
+                let _ = self.origins.insert(path, origin.to_string());
+            }
+        }
+    }
+
+    /// Warnings about settings naming crates that are not among `crate_names`.
+    pub fn crate_warnings(&self, crate_names: &[&str]) -> Vec<String> {
+        let mut warnings: Vec<String> = self
+            .unmatched_overrides(crate_names)
+            .into_iter()
+            .map(|key| format!("[parser.{}] matches no workspace crate", toml_key(key)))
+            .collect();
+
+        for rule in &self.map {
+            for crate_name in rule.crates.iter().filter(|name| !crate_names.contains(&name.as_str())) {
+                warnings.push(format!("[[map]] `{}` names unknown crate `{crate_name}`", rule.pattern));
+            }
+        }
+
+        let mut crate_lists = vec![
+            ("ignore_crates".to_string(), &self.ignore_crates),
+            ("always_crates".to_string(), &self.always_crates),
+        ];
+        crate_lists.extend(self.groups.iter().map(|(name, members)| (format!("[groups] `{name}`"), members)));
+        for (setting, patterns) in crate_lists {
+            for pattern in patterns {
+                let glob = Pattern::new(pattern).ok();
+                if !crate_names.iter().any(|name| glob.as_ref().is_some_and(|glob| glob.matches(name))) {
+                    warnings.push(format!("{setting} `{pattern}` matches no workspace crate"));
+                }
+            }
+        }
+
+        for wire in &self.trip_wire_patterns {
+            for crates in wire.crates.iter().filter(|crates| {
+                let pattern = Pattern::new(crates).ok();
+                !crate_names
+                    .iter()
+                    .any(|name| pattern.as_ref().is_some_and(|pattern| pattern.matches(name)))
+            }) {
+                warnings.push(format!("trip wire `{}` crates `{crates}` match no workspace crate", wire.pattern));
+            }
+            if let Some(crate_name) = wire.dependents_of.as_ref().filter(|name| !crate_names.contains(&name.as_str())) {
+                warnings.push(format!("trip wire `{}` names unknown crate `{crate_name}`", wire.pattern));
+            }
+        }
+
+        warnings
+    }
+
+    /// `[parser.<crate>]` overrides whose name or glob matches none of `crate_names`.
+    pub fn unmatched_overrides(&self, crate_names: &[&str]) -> Vec<&str> {
+        self.parser
+            .overrides
+            .keys()
+            .filter(|key| {
+                let pattern = Pattern::new(key).ok();
+                !crate_names
+                    .iter()
+                    .any(|name| key == name || pattern.as_ref().is_some_and(|pattern| pattern.matches(name)))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Reads parser overrides from `[package.metadata.delta]` of the given crates. Returns the
+    /// names of the crates that have one.
+    pub fn add_package_configs(&mut self, crates: &[&CargoCrate]) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for crate_ in crates {
+            let Some(delta) = crate_.metadata.as_ref().and_then(|metadata| metadata.get("delta")) else {
+                continue;
+            };
+
+            let invalid = |message: String| Error::PackageConfig {
+                package: crate_.name.clone(),
+                message,
+            };
+
+            let package_config: PackageConfig = serde_json::from_value(delta.clone()).map_err(|e| invalid(e.to_string()))?;
+
+            if let Some(parser) = package_config.parser {
+                for (key, value) in &parser {
+                    check_setting(key, value).map_err(invalid)?;
+                }
+
+                let layers: Vec<_> = self.parser.layers_for(&crate_.name).chain([&parser]).collect();
+                let _ = apply_layers(&self.parser.global, &layers).map_err(|e| invalid(e.message().to_string()))?;
+
+                let _ = self.package_configs.insert(crate_.name.clone(), parser);
+                names.push(crate_.name.clone());
+            }
+        }
+
+        Ok(names)
     }
 }
 
-pub fn load_config(config_path: Option<PathBuf>) -> Result<MainConfig> {
-    match config_path {
-        Some(path) => {
-            let content = std::fs::read_to_string(&path).map_err(Error::ConfigRead)?;
+/// Applies override layers to `base` field by field. `extend_<field>` and `remove_<field>` add to or
+/// remove from a list instead of replacing it.
+fn apply_layers(base: &ParserConfig, layers: &[&toml::Table]) -> core::result::Result<ParserConfig, toml::de::Error> {
+    let mut merged = toml::Table::try_from(base).map_err(toml::de::Error::custom)?;
+
+    for layer in layers {
+        // Settings replace values first, so that list operators in the same layer apply to them.
+        for (key, value) in *layer {
+            if !key.starts_with("extend_") && !key.starts_with("remove_") {
+                let _ = merged.insert(key.clone(), value.clone());
+            }
+        }
+
+        for (key, value) in *layer {
+            let list_op = key
+                .strip_prefix("extend_")
+                .map(|field| (field, true))
+                .or_else(|| key.strip_prefix("remove_").map(|field| (field, false)));
+
+            let Some((field, extend)) = list_op else {
+                continue;
+            };
 
-            let config: MainConfig = toml::from_str(&content)?;
+            let Some(toml::Value::Array(list)) = merged.get_mut(field) else {
+                return Err(toml::de::Error::custom(format!("`{key}` does not name a list setting")));
+            };
+            let toml::Value::Array(items) = value else {
+                return Err(toml::de::Error::custom(format!("`{key}` must be a list")));
+            };
 
-            Ok(config)
+            if extend {
+                for item in items {
+                    if !list.contains(item) {
+                        list.push(item.clone());
+                    }
+                }
+            } else {
+                list.retain(|item| !items.contains(item));
+            }
         }
-        None => Ok(MainConfig::default()),
     }
+
+    toml::Value::Table(merged).try_into()
+}
+
+/// Writes settings as TOML, each followed by a comment naming its origin.
+pub fn write_settings_toml(out: &mut impl std::io::Write, settings: &[Setting]) -> std::io::Result<()> {
+    let mut table: &[String] = &[];
+
+    for setting in settings {
+        let Some((key, parents)) = setting.path.split_last() else {
+            continue;
+        };
+
+        if parents != table {
+            let header: Vec<String> = parents.iter().map(|key| toml_key(key)).collect();
+            writeln!(out, "\n[{}]", header.join("."))?;
+            table = parents;
+        }
+
+        writeln!(out, "{} = {} # {}", toml_key(key), setting.value, setting.origin)?;
+    }
+
+    Ok(())
+}
+
+/// Settings as nested JSON objects, with `{ "value": .., "origin": .. }` leaves.
+pub fn settings_to_json(settings: &[Setting]) -> serde_json::Value {
+    fn insert(table: &mut serde_json::Map<String, serde_json::Value>, path: &[String], leaf: serde_json::Value) {
+        match path {
+            [key] => {
+                let _ = table.insert(key.clone(), leaf);
+            }
+            [parent, rest @ ..] => {
+                let entry = table
+                    .entry(parent.clone())
+                    .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+                if let serde_json::Value::Object(nested) = entry {
+                    insert(nested, rest, leaf);
+                }
+            }
+            [] => {}
+        }
+    }
+
+    let mut root = serde_json::Map::new();
+    for setting in settings {
+        let leaf = serde_json::json!({
+            "value": serde_json::to_value(&setting.value).unwrap_or_default(),
+            "origin": setting.origin,
+        });
+        insert(&mut root, &setting.path, leaf);
+    }
+
+    serde_json::Value::Object(root)
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+/// Sorts lists of strings, which are sets in the parser settings.
+fn sorted(value: toml::Value) -> toml::Value {
+    match value {
+        toml::Value::Array(mut items) if items.iter().all(toml::Value::is_str) => {
+            items.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+            toml::Value::Array(items)
+        }
+        value => value,
+    }
+}
+
+/// Finds the configuration of the workspace containing `start`: `delta.toml`, then
+/// `.cargo/delta.toml`, then `[workspace.metadata.delta]` in the root manifest.
+pub fn discover(start: &Path) -> Option<ConfigSource> {
+    let root = find_workspace_root(start)?;
+
+    for candidate in [root.join("delta.toml"), root.join(".cargo").join("delta.toml")] {
+        if candidate.is_file() {
+            return Some(ConfigSource::File(candidate));
+        }
+    }
+
+    let manifest = root.join("Cargo.toml");
+    workspace_metadata(&manifest)
+        .ok()?
+        .map(|_| ConfigSource::WorkspaceMetadata(manifest))
+}
+
+/// The directory of the nearest manifest with a `[workspace]` table, or of the nearest manifest
+/// for packages outside of a workspace.
+fn find_workspace_root(start: &Path) -> Option<PathBuf> {
+    let mut package_root = None;
+
+    for dir in start.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        let Ok(content) = std::fs::read_to_string(&manifest) else {
+            continue;
+        };
+
+        if content.parse::<toml::Table>().is_ok_and(|table| table.contains_key("workspace")) {
+            return Some(dir.to_path_buf());
+        }
+        let _ = package_root.get_or_insert_with(|| dir.to_path_buf());
+    }
+
+    package_root
+}
+
+fn workspace_metadata(manifest: &Path) -> Result<Option<toml::Value>> {
+    let content = std::fs::read_to_string(manifest).map_err(Error::ConfigRead)?;
+    let mut table: toml::Table = toml::from_str(&content).map_err(|source| Error::ConfigParse {
+        file: manifest.display().to_string(),
+        source,
+    })?;
+
+    let delta = table
+        .remove("workspace")
+        .and_then(|workspace| workspace.get("metadata")?.get("delta").cloned());
+
+    Ok(delta)
+}
+
+/// The parts of a root manifest holding `[workspace.metadata.delta]`, deserialized straight from the
+/// manifest so errors point at its lines.
+#[derive(Deserialize)]
+struct Manifest {
+    workspace: Option<ManifestWorkspace>,
+}
+
+#[derive(Deserialize)]
+struct ManifestWorkspace {
+    metadata: Option<ManifestMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ManifestMetadata {
+    delta: Option<MainConfig>,
+}
+
+/// Top-level settings of `MainConfig`, the tables `DELTA_*` environment variables may address.
+const SETTINGS: [&str; 10] = [
+    "parser",
+    "git",
+    "file_exclude_patterns",
+    "trip_wire_patterns",
+    "default_trip_wires",
+    "ignore_crates",
+    "always_crates",
+    "groups",
+    "extends",
+    "map",
+];
+
+/// Built-in presets for `extends = ["preset:<name>"]`.
+const PRESETS: [(&str, &str); 4] = [
+    ("askama", include_str!("../presets/askama.toml")),
+    ("grpc", include_str!("../presets/grpc.toml")),
+    ("insta", include_str!("../presets/insta.toml")),
+    ("sqlx", include_str!("../presets/sqlx.toml")),
+];
+
+/// A single setting layered over the config file, from `--set` or a `DELTA_*` environment variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOverride {
+    /// The setting as a (nested) table, e.g. `{ git = { remote_branch = "origin/main" } }`.
+    pub layer: toml::Table,
+    /// Where the override came from, e.g. `--set git.remote_branch`.
+    pub origin: String,
+}
+
+impl ConfigOverride {
+    /// Parses `key=value`, where the key is a dotted TOML key and the value a TOML value, or a
+    /// string if it is not one.
+    pub fn parse(assignment: &str, origin: String) -> Result<Self> {
+        let invalid = |message: String| Error::ConfigOverride {
+            origin: origin.clone(),
+            message,
+        };
+
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("expected KEY=VALUE, found `{assignment}`")))?;
+        let (key, value) = (key.trim(), value.trim());
+
+        let layer = toml::from_str::<toml::Table>(&format!("{key} = {value}"))
+            .or_else(|_| toml::from_str(&format!("{key} = {}", toml::Value::String(value.to_string()))))
+            .map_err(|e| invalid(e.message().to_string()))?;
+
+        let _ = toml::Value::Table(layer.clone())
+            .try_into::<MainConfig>()
+            .map_err(|e| invalid(e.message().to_string()))?;
+
+        Ok(Self { layer, origin })
+    }
+
+    /// Parses `--set` arguments.
+    pub fn from_args(assignments: &[String]) -> Result<Vec<Self>> {
+        assignments
+            .iter()
+            .map(|assignment| {
+                let key = assignment.split_once('=').map_or(assignment.as_str(), |(key, _)| key.trim());
+                Self::parse(assignment, format!("--set {key}"))
+            })
+            .collect()
+    }
+
+    /// Parses `DELTA_<KEY>` environment variables, where `__` separates tables, e.g.
+    /// `DELTA_GIT__REMOTE_BRANCH`. Variables not naming a setting are ignored, as other tools share
+    /// the prefix.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Vec<Self>> {
+        let mut overrides = Vec::new();
+
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix("DELTA_") else {
+                continue;
+            };
+
+            let path: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+            if !path.first().is_some_and(|setting| SETTINGS.contains(&setting.as_str())) {
+                continue;
+            }
+
+            let dotted: Vec<String> = path.iter().map(|key| toml_key(key)).collect();
+            overrides.push(Self::parse(&format!("{}={value}", dotted.join(".")), name)?);
+        }
+
+        overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+        Ok(overrides)
+    }
+}
+
+/// Merges `layer` into `table`, replacing values and merging nested tables. List operators add up,
+/// and a replaced list drops the operators of lower layers on it.
+fn merge_tables(table: &mut toml::Table, layer: &toml::Table) {
+    for (key, value) in layer {
+        let is_list_op = key.starts_with("extend_") || key.starts_with("remove_");
+        if !is_list_op {
+            let _ = table.remove(&format!("extend_{key}"));
+            let _ = table.remove(&format!("remove_{key}"));
+        }
+
+        match (table.get_mut(key), value) {
+            (Some(toml::Value::Table(nested)), toml::Value::Table(layer)) => merge_tables(nested, layer),
+            (Some(toml::Value::Array(list)), toml::Value::Array(items)) if is_list_op => list.extend(items.iter().cloned()),
+            _ => {
+                let _ = table.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Collects the layers of a config table, lowest first: the configs it extends, then the table
+/// itself. Relative paths in `extends` are resolved against `base_dir`.
+fn collect_layers(
+    table: toml::Table,
+    origin: String,
+    base_dir: Option<&Path>,
+    chain: &mut Vec<String>,
+    layers: &mut Vec<(toml::Table, String)>,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let extends: Vec<String> = table
+        .get("extends")
+        .and_then(toml::Value::as_array)
+        .map(|extends| extends.iter().filter_map(toml::Value::as_str).map(ToString::to_string).collect())
+        .unwrap_or_default();
+
+    for base in extends {
+        let invalid = |message: String| Error::ConfigExtends {
+            base: base.clone(),
+            message,
+        };
+
+        let (content, base_origin, base_path) = if let Some(name) = base.strip_prefix("preset:") {
+            let (_, content) = PRESETS.iter().find(|(preset, _)| *preset == name).ok_or_else(|| {
+                let names: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+                invalid(format!("unknown preset, expected one of {}", names.join(", ")))
+            })?;
+            ((*content).to_string(), base.clone(), None)
+        } else {
+            let path = base_dir.map_or_else(|| PathBuf::from(&base), |dir| dir.join(&base));
+            let content = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+            (content, path.display().to_string(), Some(path))
+        };
+
+        if chain.contains(&base_origin) {
+            return Err(invalid(format!("cyclic extends through {}", chain.join(", "))));
+        }
+
+        let parse_error = |source| Error::ConfigParse {
+            file: base_origin.clone(),
+            source,
+        };
+        let _ = toml::from_str::<MainConfig>(&content).map_err(parse_error)?;
+        let base_table = toml::from_str::<toml::Table>(&content).map_err(parse_error)?;
+
+        chain.push(base_origin.clone());
+        let base_dir = base_path.as_deref().and_then(Path::parent).map(Path::to_path_buf);
+        files.extend(base_path);
+        collect_layers(base_table, base_origin, base_dir.as_deref(), chain, layers, files)?;
+        let _ = chain.pop();
+    }
+
+    layers.push((table, origin));
+    Ok(())
+}
+
+/// Loads the config from `source`, with `overrides` layered over it.
+pub fn load_config(source: Option<&ConfigSource>, overrides: &[ConfigOverride]) -> Result<MainConfig> {
+    let (config, mut table) = match source {
+        Some(source) => load_source(source)?,
+        None => (MainConfig::default(), toml::Table::new()),
+    };
+
+    if overrides.is_empty() {
+        return Ok(config);
+    }
+
+    for config_override in overrides {
+        merge_tables(&mut table, &config_override.layer);
+    }
+
+    let mut merged: MainConfig = toml::Value::Table(table).try_into().map_err(|e| Error::ConfigOverride {
+        origin: overrides.iter().map(|o| o.origin.as_str()).collect::<Vec<_>>().join(", "),
+        message: e.message().to_string(),
+    })?;
+
+    merged.origins = config.origins;
+    merged.files = config.files;
+    for config_override in overrides {
+        merged.record_origins(&[], &config_override.layer, &config_override.origin);
+    }
+
+    Ok(merged)
+}
+
+/// The config in `source` and its raw table.
+fn load_source(source: &ConfigSource) -> Result<(MainConfig, toml::Table)> {
+    let (ConfigSource::File(path) | ConfigSource::WorkspaceMetadata(path)) = source;
+    let content = std::fs::read_to_string(path).map_err(Error::ConfigRead)?;
+    let parse_error = |source| Error::ConfigParse {
+        file: path.display().to_string(),
+        source,
+    };
+
+    let (mut config, table) = match source {
+        ConfigSource::File(_) => (
+            toml::from_str::<MainConfig>(&content).map_err(parse_error)?,
+            toml::from_str::<toml::Table>(&content).map_err(parse_error)?,
+        ),
+        ConfigSource::WorkspaceMetadata(_) => {
+            let manifest: Manifest = toml::from_str(&content).map_err(parse_error)?;
+            let delta = manifest.workspace.and_then(|workspace| workspace.metadata?.delta);
+            let table = match workspace_metadata(path)? {
+                Some(toml::Value::Table(table)) => table,
+                _ => toml::Table::new(),
+            };
+
+            (delta.unwrap_or_default(), table)
+        }
+    };
+
+    let mut layers = Vec::new();
+    let mut files = match source {
+        ConfigSource::File(path) => vec![path.clone()],
+        ConfigSource::WorkspaceMetadata(_) => Vec::new(),
+    };
+    collect_layers(
+        table,
+        source.to_string(),
+        path.parent(),
+        &mut vec![source.to_string()],
+        &mut layers,
+        &mut files,
+    )?;
+
+    if let [(table, origin)] = layers.as_slice() {
+        config.record_origins(&[], table, origin);
+        config.files = files;
+        return Ok((config, table.clone()));
+    }
+
+    let mut merged = toml::Table::new();
+    for (layer, _) in &layers {
+        merge_tables(&mut merged, layer);
+    }
+
+    let mut config: MainConfig = toml::Value::Table(merged.clone()).try_into().map_err(|e| Error::ConfigExtends {
+        base: source.to_string(),
+        message: e.message().to_string(),
+    })?;
+    for (layer, origin) in &layers {
+        config.record_origins(&[], layer, origin);
+    }
+    config.files = files;
+
+    Ok((config, merged))
 }
 
 #[cfg(test)]
@@ -123,7 +1232,37 @@ mod tests {
         assert!(config.file_methods.contains("load"));
         assert!(config.include_macros.contains("include_str"));
         assert!(config.include_macros.contains("include_bytes"));
+        assert!(config.source_include_macros.contains("include"));
         assert!(config.mod_macros.is_empty());
+        assert!(config.dir_refs);
+        assert!(config.dir_macros.contains("include_dir"));
+        assert!(config.dir_methods.contains("read_dir"));
+        assert!(config.attributes);
+        assert!(config.macro_bodies);
+        assert!(config.build_inputs);
+        assert!(config.build_methods.contains("compile_protos"));
+        assert!(config.attribute_refs.is_empty());
+    }
+
+    #[test]
+    fn attribute_refs_parse_from_toml() {
+        let config: MainConfig = toml::from_str(
+            r#"
+            [parser]
+            attribute_refs = [
+                { attribute = "template", key = "path", base = "templates" },
+                { attribute = "folder", relative_to = "file", directory = true },
+            ]
+            "#,
+        )
+        .unwrap();
+        let refs = &config.parser.global.attribute_refs;
+        assert_eq!(refs[0].key.as_deref(), Some("path"));
+        assert_eq!(refs[0].relative_to, RelativeTo::Crate);
+        assert_eq!(refs[0].base.as_deref(), Some("templates"));
+        assert!(refs[1].key.is_none());
+        assert_eq!(refs[1].relative_to, RelativeTo::File);
+        assert!(refs[1].directory);
     }
 
     #[test]
@@ -143,16 +1282,394 @@ mod tests {
         assert!(parser.mods);
     }
 
+    #[test]
+    fn crate_overrides_inherit_global_fields() {
+        let config: MainConfig = toml::from_str(
+            r#"
+            [parser]
+            file_refs = false
+            assume_patterns = ["*.sql"]
+
+            [parser."svc-*"]
+            mods = false
+            extend_assume_patterns = ["*.proto"]
+            remove_file_methods = ["join"]
+
+            [parser.svc-api]
+            assume_patterns = ["*.graphql"]
+            "#,
+        )
+        .unwrap();
+
+        let svc = config.crate_config("svc-db");
+        assert!(!svc.file_refs);
+        assert!(!svc.mods);
+        assert_eq!(svc.assume_patterns, HashSet::from(["*.sql".to_string(), "*.proto".to_string()]));
+        assert!(svc.file_methods.contains("open"));
+        assert!(!svc.file_methods.contains("join"));
+
+        let api = config.crate_config("svc-api");
+        assert!(!api.mods);
+        assert_eq!(api.assume_patterns, HashSet::from(["*.graphql".to_string()]));
+
+        let other = config.crate_config("other");
+        assert!(other.mods);
+        assert!(other.file_methods.contains("join"));
+    }
+
+    #[test]
+    fn crate_override_rejects_list_operator_on_non_list() {
+        let result: core::result::Result<MainConfig, _> = toml::from_str(
+            "
+            [parser.my-crate]
+            extend_mods = [true]
+            ",
+        );
+        assert!(result.unwrap_err().to_string().contains("extend_mods"));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected_with_line() {
+        let error = toml::from_str::<MainConfig>("[parser]\nmods = true\nfle_refs = true\n").unwrap_err();
+        assert!(error.to_string().contains("line 3"));
+        assert!(error.to_string().contains("unknown field `fle_refs`"));
+
+        let error = toml::from_str::<MainConfig>("[parser.my-crate]\nmods = true\nfle = 1\n").unwrap_err();
+        assert!(error.to_string().contains("in [parser.my-crate]: unknown field `fle`"));
+
+        let error = toml::from_str::<MainConfig>("[git]\nremote = \"origin/main\"\n").unwrap_err();
+        assert!(error.to_string().contains("unknown field `remote`"));
+    }
+
+    #[test]
+    fn invalid_globs_are_rejected() {
+        let error = toml::from_str::<MainConfig>("trip_wire_patterns = [\"Cargo.lock\", \"[\"]\n").unwrap_err();
+        assert!(error.to_string().contains("invalid glob `[`"));
+
+        let error = toml::from_str::<MainConfig>("[parser]\nassume_patterns = [\"[\"]\n").unwrap_err();
+        assert!(error.to_string().contains("invalid glob `[`"));
+
+        let error = toml::from_str::<MainConfig>("[parser.\"svc-[\"]\nmods = true\n").unwrap_err();
+        assert!(error.to_string().contains("invalid crate glob"));
+    }
+
+    #[test]
+    fn unmatched_overrides_lists_overrides_without_crate() {
+        let config: MainConfig = toml::from_str("[parser.\"svc-*\"]\nmods = true\n\n[parser.gone]\nmods = true\n").unwrap();
+        assert_eq!(config.unmatched_overrides(&["svc-api", "other"]), vec!["gone"]);
+        assert_eq!(config.unmatched_overrides(&["other"]), vec!["gone", "svc-*"]);
+    }
+
+    #[test]
+    fn map_rules_match_paths_and_warn_on_unknown_crates() {
+        let config: MainConfig = toml::from_str(
+            r#"
+            [[map]]
+            pattern = "schemas/**/*.json"
+            crates = ["api", "gone"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.map[0].matches(Path::new("schemas/v1/user.json")));
+        assert!(!config.map[0].matches(Path::new("api/schemas/user.json")));
+        assert_eq!(
+            config.crate_warnings(&["api"]),
+            vec!["[[map]] `schemas/**/*.json` names unknown crate `gone`"]
+        );
+
+        let error = toml::from_str::<MainConfig>("[[map]]\npattern = \"[\"\ncrates = []\n").unwrap_err();
+        assert!(error.to_string().contains("invalid glob `[`"));
+    }
+
+    #[test]
+    fn trip_wires_are_plain_patterns_or_scoped_tables() {
+        let config: MainConfig = toml::from_str(
+            r#"
+            trip_wire_patterns = [
+                "Cargo.lock",
+                { pattern = "proto/**", crates = ["api-*"] },
+                { pattern = "ci/db/**", dependents_of = "db-core" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let wires = &config.trip_wire_patterns;
+        assert!(!wires[0].is_scoped());
+        assert_eq!(wires[1].crates, vec!["api-*"]);
+        assert_eq!(wires[2].dependents_of.as_deref(), Some("db-core"));
+        assert_eq!(wires[1].to_string(), "proto/** (crates api-*)");
+        assert!(wires[2].matches(Path::new("ci/db/init.sql")));
+        assert_eq!(
+            config.crate_warnings(&["api-server", "db"]),
+            vec!["trip wire `ci/db/**` names unknown crate `db-core`"]
+        );
+
+        let shown = toml::to_string(&config).unwrap();
+        assert!(shown.contains(r#"trip_wire_patterns = ["Cargo.lock", { pattern = "proto/**", crates = ["api-*"] }"#));
+
+        let error = toml::from_str::<MainConfig>("trip_wire_patterns = [{ pattern = \"a\", crate = \"b\" }]\n").unwrap_err();
+        assert!(error.to_string().contains("unknown field `crate`"));
+        let error = toml::from_str::<MainConfig>("trip_wire_patterns = [{ pattern = \"a\", crates = [\"[\"] }]\n").unwrap_err();
+        assert!(error.to_string().contains("invalid glob `[`"));
+    }
+
+    #[test]
+    fn default_trip_wires_match_workspace_configs() {
+        let defaults = DefaultTripWires::default();
+        let root = Path::new("");
+        assert_eq!(defaults.reason(Path::new("rust-toolchain.toml"), root), Some("Rust toolchain"));
+        assert_eq!(defaults.reason(Path::new("app/tests/rust-toolchain.toml"), root), None);
+        assert_eq!(defaults.reason(Path::new(".cargo/config.toml"), root), Some("Cargo configuration"));
+        assert_eq!(defaults.reason(Path::new("tools/.cargo/config.toml"), root), None);
+        assert_eq!(defaults.reason(Path::new("config.toml"), root), None);
+        assert_eq!(defaults.reason(Path::new("clippy.toml"), root), Some("Clippy configuration"));
+        assert_eq!(defaults.reason(Path::new("app/fixtures/clippy.toml"), root), None);
+        assert_eq!(defaults.reason(Path::new(".rustfmt.toml"), root), Some("rustfmt configuration"));
+
+        let workspace = Path::new("rust/workspace");
+        assert_eq!(
+            defaults.reason(Path::new("rust/workspace/clippy.toml"), workspace),
+            Some("Clippy configuration")
+        );
+        assert_eq!(defaults.reason(Path::new("clippy.toml"), workspace), None);
+        assert_eq!(
+            defaults.reason(Path::new(".cargo/config.toml"), workspace),
+            Some("Cargo configuration")
+        );
+        assert_eq!(
+            defaults.reason(Path::new("rust/.cargo/config"), workspace),
+            Some("Cargo configuration")
+        );
+
+        let config: MainConfig = toml::from_str("[default_trip_wires]\nrustfmt = false\n").unwrap();
+        assert_eq!(config.default_trip_wires.reason(Path::new("rustfmt.toml"), root), None);
+        assert!(config.default_trip_wires.toolchain);
+    }
+
+    #[test]
+    fn crate_sets_warn_about_patterns_matching_no_crate() {
+        let config: MainConfig = toml::from_str(
+            r#"
+            ignore_crates = ["xtask", "fuzz-*"]
+            always_crates = ["smoke"]
+
+            [groups]
+            frontend = ["web-*", "gone"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.crate_warnings(&["xtask", "smoke", "web-ui"]),
+            vec![
+                "ignore_crates `fuzz-*` matches no workspace crate",
+                "[groups] `frontend` `gone` matches no workspace crate"
+            ]
+        );
+        assert_eq!(
+            select_crates(&config.groups["frontend"], &["web-ui".to_string(), "web".to_string()]),
+            BTreeSet::from(["web-ui".to_string()])
+        );
+
+        let error = toml::from_str::<MainConfig>("[groups]\nfrontend = [\"[\"]\n").unwrap_err();
+        assert!(error.to_string().contains("invalid glob `[` in group `frontend`"));
+    }
+
+    #[test]
+    fn settings_name_the_layer_that_set_them() {
+        let mut config: MainConfig = toml::from_str(
+            r#"
+            [parser]
+            assume = true
+
+            [parser."svc-*"]
+            extend_assume_patterns = ["*.sql"]
+            "#,
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str("[parser]\nassume = true\n\n[parser.\"svc-*\"]\nextend_assume_patterns = []\n").unwrap();
+        config.record_origins(&[], &table, "delta.toml");
+        let _ = config
+            .package_configs
+            .insert("svc-db".to_string(), toml::from_str("mods = false").unwrap());
+
+        let settings = config.settings(Some("svc-db"));
+        let origin = |field: &str| {
+            settings
+                .iter()
+                .find(|setting| setting.path == ["parser", field])
+                .map(|setting| setting.origin.as_str())
+        };
+        assert_eq!(origin("assume"), Some("delta.toml"));
+        assert_eq!(origin("assume_patterns"), Some("[parser.\"svc-*\"] in delta.toml"));
+        assert_eq!(origin("mods"), Some("[package.metadata.delta] of svc-db"));
+        assert_eq!(origin("file_refs"), Some("default"));
+
+        let mut out = Vec::new();
+        write_settings_toml(&mut out, &config.settings(None)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("[parser.\"svc-*\"]\nextend_assume_patterns = [\"*.sql\"] # delta.toml"));
+    }
+
+    #[test]
+    fn settings_list_every_top_level_setting() {
+        let config = MainConfig {
+            git: Some(GitConfig { remote_branch: None }),
+            ..MainConfig::default()
+        };
+        let mut keys: Vec<String> = toml::Table::try_from(&config).unwrap().keys().cloned().collect();
+        let mut settings: Vec<String> = SETTINGS.iter().map(ToString::to_string).collect();
+        keys.sort();
+        settings.sort();
+        assert_eq!(keys, settings);
+    }
+
+    #[test]
+    fn overrides_parse_values_and_fall_back_to_strings() {
+        let overrides = ConfigOverride::from_args(&[
+            "git.remote_branch=origin/release".to_string(),
+            "parser.assume=true".to_string(),
+            "parser.\"svc-*\".extend_assume_patterns=[\"*.sql\"]".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(overrides[0].origin, "--set git.remote_branch");
+        assert_eq!(overrides[0].layer["git"]["remote_branch"].as_str(), Some("origin/release"));
+        assert_eq!(overrides[1].layer["parser"]["assume"].as_bool(), Some(true));
+        assert!(overrides[2].layer["parser"]["svc-*"].is_table());
+
+        let error = ConfigOverride::from_args(&["parser.asume=true".to_string()]).unwrap_err();
+        assert!(error.to_string().contains("--set parser.asume"));
+        assert!(error.to_string().contains("unknown field `asume`"));
+
+        let error = ConfigOverride::from_args(&["parser.assume=maybe".to_string()]).unwrap_err();
+        assert!(error.to_string().contains("invalid type"));
+    }
+
+    #[test]
+    fn overrides_read_delta_environment_variables() {
+        let overrides = ConfigOverride::from_env([
+            ("DELTA_PARSER__ASSUME".to_string(), "true".to_string()),
+            ("DELTA_GIT__REMOTE_BRANCH".to_string(), "origin/release".to_string()),
+            ("DELTA_PAGER".to_string(), "less".to_string()),
+            ("PATH".to_string(), "/bin".to_string()),
+        ])
+        .unwrap();
+
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides[0].origin, "DELTA_GIT__REMOTE_BRANCH");
+        assert_eq!(overrides[0].layer["git"]["remote_branch"].as_str(), Some("origin/release"));
+        assert_eq!(overrides[1].layer["parser"]["assume"].as_bool(), Some(true));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn overrides_are_layered_over_the_config_file() {
+        let path = std::env::temp_dir().join("cargo_delta_test_overrides.toml");
+        std::fs::write(
+            &path,
+            "trip_wire_patterns = [\"Cargo.lock\"]\n\n[parser]\nassume = false\nmods = false\n",
+        )
+        .unwrap();
+
+        let overrides = ConfigOverride::from_args(&["parser.assume=true".to_string()]).unwrap();
+        let config = load_config(Some(&ConfigSource::File(path.clone())), &overrides).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(config.parser.global.assume);
+        assert!(!config.parser.global.mods);
+        assert_eq!(config.trip_wire_patterns, vec![TripWire::from("Cargo.lock")]);
+
+        let settings = config.settings(None);
+        let origin = |path: &[&str]| {
+            settings
+                .iter()
+                .find(|setting| setting.path == path)
+                .map(|setting| setting.origin.clone())
+        };
+        assert_eq!(origin(&["parser", "assume"]).as_deref(), Some("--set parser.assume"));
+        assert_eq!(origin(&["parser", "mods"]), Some(path.display().to_string()));
+    }
+
+    #[test]
+    fn presets_are_valid_configs() {
+        for (name, content) in PRESETS {
+            let config: MainConfig = toml::from_str(content).unwrap_or_else(|e| panic!("preset {name}: {e}"));
+            assert!(config.parser.global.assume, "preset {name}");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn extends_merges_bases_below_local_values() {
+        let root = std::env::temp_dir().join("cargo_delta_test_extends");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("shared")).unwrap();
+        std::fs::write(
+            root.join("shared").join("base.toml"),
+            "trip_wire_patterns = [\"Cargo.lock\"]\n\n[parser]\nmods = false\nextend_assume_patterns = [\"*.graphql\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("delta.toml"),
+            "extends = [\"shared/base.toml\", \"preset:insta\"]\n\n[parser]\nmods = true\nextend_assume_patterns = [\"*.sql\"]\n",
+        )
+        .unwrap();
+
+        let config = load_config(Some(&ConfigSource::File(root.join("delta.toml"))), &[]).unwrap();
+
+        assert_eq!(config.trip_wire_patterns, vec![TripWire::from("Cargo.lock")]);
+        assert_eq!(config.files, vec![root.join("delta.toml"), root.join("shared").join("base.toml")]);
+        assert!(config.parser.global.mods);
+        assert!(config.parser.global.assume);
+        assert_eq!(
+            config.parser.global.assume_patterns,
+            HashSet::from(["*.graphql".to_string(), "*.snap".to_string(), "*.sql".to_string()])
+        );
+
+        let settings = config.settings(None);
+        let origin = |path: &[&str]| {
+            settings
+                .iter()
+                .find(|setting| setting.path == path)
+                .map(|setting| setting.origin.clone())
+        };
+        assert_eq!(origin(&["parser", "assume"]).as_deref(), Some("preset:insta"));
+        assert_eq!(
+            origin(&["trip_wire_patterns"]),
+            Some(root.join("shared").join("base.toml").display().to_string())
+        );
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn extends_rejects_unknown_presets_and_cycles() {
+        let root = std::env::temp_dir().join("cargo_delta_test_extends_errors");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        std::fs::write(root.join("delta.toml"), "extends = [\"preset:nope\"]\n").unwrap();
+        let error = load_config(Some(&ConfigSource::File(root.join("delta.toml"))), &[]).unwrap_err();
+        assert!(error.to_string().contains("unknown preset"));
+
+        std::fs::write(root.join("delta.toml"), "extends = [\"base.toml\"]\n").unwrap();
+        std::fs::write(root.join("base.toml"), "extends = [\"delta.toml\"]\n").unwrap();
+        let error = load_config(Some(&ConfigSource::File(root.join("delta.toml"))), &[]).unwrap_err();
+        assert!(error.to_string().contains("cyclic extends"));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
     #[test]
     fn load_config_returns_default_when_none() {
-        let config = load_config(None).unwrap();
+        let config = load_config(None, &[]).unwrap();
         assert!(config.file_exclude_patterns.contains(&".*".to_string()));
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
     fn load_config_returns_error_for_missing_file() {
-        let result = load_config(Some(PathBuf::from("nonexistent-config.toml")));
+        let result = load_config(Some(&ConfigSource::File(PathBuf::from("nonexistent-config.toml"))), &[]);
         assert!(matches!(result, Err(Error::ConfigRead(_))));
     }
 
@@ -168,10 +1685,10 @@ mods = false
 "#;
         let config: MainConfig = toml::from_str(toml_str).unwrap();
         assert_eq!(config.file_exclude_patterns, vec!["build"]);
-        assert_eq!(config.trip_wire_patterns, vec!["Cargo.lock"]);
-        assert!(!config.parser.file_refs);
-        assert!(!config.parser.mods);
-        assert!(config.parser.includes);
+        assert_eq!(config.trip_wire_patterns, vec![TripWire::from("Cargo.lock")]);
+        assert!(!config.parser.global.file_refs);
+        assert!(!config.parser.global.mods);
+        assert!(config.parser.global.includes);
     }
 
     #[test]
@@ -183,4 +1700,53 @@ remote_branch = "origin/develop"
         let config: MainConfig = toml::from_str(toml_str).unwrap();
         assert_eq!(config.git.unwrap().remote_branch.unwrap(), "origin/develop");
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn discover_prefers_delta_toml_then_cargo_dir_then_metadata() {
+        let root = std::env::temp_dir().join("cargo_delta_test_discover");
+        let _ = std::fs::remove_dir_all(&root);
+        let member = root.join("crates").join("a");
+        std::fs::create_dir_all(&member).unwrap();
+        std::fs::create_dir_all(root.join(".cargo")).unwrap();
+        std::fs::write(member.join("Cargo.toml"), "[package]\nname = \"a\"\n").unwrap();
+        std::fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"crates/a\"]\n\n[workspace.metadata.delta]\ntrip_wire_patterns = [\"Cargo.lock\"]\n",
+        )
+        .unwrap();
+
+        let manifest = root.join("Cargo.toml");
+        assert_eq!(discover(&member), Some(ConfigSource::WorkspaceMetadata(manifest.clone())));
+        let config = load_config(Some(&ConfigSource::WorkspaceMetadata(manifest)), &[]).unwrap();
+        assert_eq!(config.trip_wire_patterns, vec![TripWire::from("Cargo.lock")]);
+
+        std::fs::write(root.join(".cargo").join("delta.toml"), "").unwrap();
+        assert_eq!(discover(&member), Some(ConfigSource::File(root.join(".cargo").join("delta.toml"))));
+
+        std::fs::write(root.join("delta.toml"), "").unwrap();
+        assert_eq!(discover(&member), Some(ConfigSource::File(root.join("delta.toml"))));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn package_metadata_overrides_parser_config() {
+        let crate_ = CargoCrate {
+            name: "my-crate".to_string(),
+            source: None,
+            targets: vec![],
+            manifest_path: PathBuf::from("Cargo.toml"),
+            dependencies: vec![],
+            features: BTreeMap::new(),
+            metadata: Some(serde_json::json!({ "delta": { "parser": { "file_refs": false } } })),
+        };
+
+        let mut config: MainConfig = toml::from_str("[parser.my-crate]\nmods = false\n").unwrap();
+        let names = config.add_package_configs(&[&crate_]).unwrap();
+        assert_eq!(names, vec!["my-crate"]);
+        assert!(!config.crate_config("my-crate").file_refs);
+        assert!(!config.crate_config("my-crate").mods);
+        assert!(config.crate_config("other").file_refs);
+    }
 }
@@ -1,29 +1,62 @@
-use crate::cargo::CargoMetadata;
+use crate::cargo::{CargoCrate, CargoMetadata};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[expect(clippy::struct_field_names, reason = "field names are keys of the analysis document")]
 pub struct Crates {
-    crates: HashMap<String, Vec<String>>,
+    crates: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    kinds: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    features: BTreeMap<String, BTreeMap<String, Feature>>,
+    /// Directory names of the manifests, which name the crate nodes of the file tree.
+    #[serde(default)]
+    dirs: BTreeMap<String, String>,
+}
+
+/// A cargo feature, resolved to the features of the same crate and the optional workspace
+/// dependencies it enables.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Feature {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crates: Vec<String>,
 }
 
 pub fn parse(metadata: &CargoMetadata) -> Crates {
     let mut workspace = HashSet::new();
-    let mut dependencies = HashMap::new();
+    let mut dependencies = BTreeMap::new();
+    let mut kinds = BTreeMap::new();
+    let mut dirs = BTreeMap::new();
 
     for package in &metadata.packages {
         if package.source.is_some() {
             continue;
         }
         let _ = workspace.insert(package.name.clone());
+
+        if let Some(dir) = package.manifest_path.parent().and_then(|dir| dir.file_name()) {
+            let _ = dirs.insert(package.name.clone(), dir.to_string_lossy().to_string());
+        }
         let _ = dependencies.insert(package.name.clone(), Vec::new());
+
+        let mut package_kinds: Vec<String> = package.targets.iter().flat_map(|target| target.kind.iter().cloned()).collect();
+        package_kinds.sort();
+        package_kinds.dedup();
+        let _ = kinds.insert(package.name.clone(), package_kinds);
     }
 
+    let mut features = BTreeMap::new();
+
     for package in &metadata.packages {
         if package.source.is_some() {
             continue;
         }
 
+        let _ = features.insert(package.name.clone(), parse_features(package, &workspace));
+
         for dep in &package.dependencies {
             if dep.source.is_some() || !workspace.contains(&dep.name) {
                 continue;
@@ -37,7 +70,69 @@ pub fn parse(metadata: &CargoMetadata) -> Crates {
         }
     }
 
-    Crates { crates: dependencies }
+    for package_deps in dependencies.values_mut() {
+        package_deps.sort();
+    }
+
+    Crates {
+        crates: dependencies,
+        kinds,
+        features,
+        dirs,
+    }
+}
+
+fn parse_features(package: &CargoCrate, workspace: &HashSet<String>) -> BTreeMap<String, Feature> {
+    // Optional workspace dependencies by the name features refer to them with.
+    let optional: HashMap<&str, &String> = package
+        .dependencies
+        .iter()
+        .filter(|dep| dep.optional && dep.source.is_none() && workspace.contains(&dep.name))
+        .map(|dep| (dep.rename.as_deref().unwrap_or(&dep.name), &dep.name))
+        .collect();
+
+    let mut explicit_deps = HashSet::new();
+    let mut features = BTreeMap::new();
+
+    for (name, values) in &package.features {
+        let mut feature = Feature::default();
+
+        for value in values {
+            if let Some(dep) = value.strip_prefix("dep:") {
+                let _ = explicit_deps.insert(dep);
+                feature.crates.extend(optional.get(dep).map(|dep| (*dep).clone()));
+            } else if let Some((dep, _)) = value.split_once('/') {
+                // `dep?/feat` only enables `feat` when `dep` is enabled otherwise, it does not
+                // enable `dep` itself.
+                if !dep.ends_with('?') {
+                    feature.crates.extend(optional.get(dep).map(|dep| (*dep).clone()));
+                }
+            } else if package.features.contains_key(value) {
+                feature.features.push(value.clone());
+            } else {
+                feature.crates.extend(optional.get(value.as_str()).map(|dep| (*dep).clone()));
+            }
+        }
+
+        feature.features.sort();
+        feature.features.dedup();
+        feature.crates.sort();
+        feature.crates.dedup();
+        let _ = features.insert(name.clone(), feature);
+    }
+
+    // Optional dependencies not referenced with `dep:` get an implicit feature of the same name.
+    for (key, dep) in optional {
+        if !explicit_deps.contains(key) && !features.contains_key(key) {
+            let feature = Feature {
+                features: Vec::new(),
+                crates: vec![dep.clone()],
+            };
+            let _ = features.insert(key.to_string(), feature);
+        }
+    }
+
+    features
 }
 
 impl Crates {
@@ -45,6 +140,10 @@ impl Crates {
         self.crates.get(crate_name)
     }
 
+    pub fn get_kinds(&self, crate_name: &str) -> Option<&Vec<String>> {
+        self.kinds.get(crate_name)
+    }
+
     pub fn get_dependents(&self, crate_name: &str) -> Option<Vec<String>> {
         if !self.crates.contains_key(crate_name) {
             return None;
@@ -115,6 +214,103 @@ impl Crates {
         Some(all_dependents.into_iter().collect())
     }
 
+    /// Returns the crates of the given set that no other crate of the set depends on, directly or
+    /// indirectly. Building these builds the whole set. Crates in a dependency cycle are kept.
+    pub fn get_roots(&self, subset: &BTreeSet<String>) -> BTreeSet<String> {
+        let transitive: HashMap<&String, HashSet<String>> = subset
+            .iter()
+            .map(|name| {
+                let deps = self.get_dependencies_transitive(name).unwrap_or_default();
+                (name, deps.into_iter().collect())
+            })
+            .collect();
+
+        subset
+            .iter()
+            .filter(|name| {
+                !transitive
+                    .iter()
+                    .any(|(other, deps)| other != name && deps.contains(*name) && !transitive[name].contains(*other))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Groups the given crates by dependency depth, so that every crate only depends on crates
+    /// from earlier layers. Crates caught in a dependency cycle (e.g. through dev-dependencies)
+    /// share a layer, and the crates depending on the cycle come after it.
+    pub fn get_layers(&self, subset: &BTreeSet<String>) -> Vec<Vec<String>> {
+        let mut remaining: BTreeSet<&String> = subset.iter().filter(|name| self.crates.contains_key(*name)).collect();
+        let transitive: HashMap<&String, HashSet<String>> = remaining
+            .iter()
+            .map(|name| {
+                let deps = self.get_dependencies_transitive(name).unwrap_or_default();
+                (*name, deps.into_iter().collect())
+            })
+            .collect();
+        let mut layers = Vec::new();
+
+        while !remaining.is_empty() {
+            // Crates of a cycle wait for each other, they are ready once none of them waits on
+            // a crate outside the cycle.
+            let in_cycle = |a: &String, b: &String| transitive[a].contains(b) && transitive[b].contains(a);
+            let unblocked: BTreeSet<&String> = remaining
+                .iter()
+                .filter(|name| {
+                    self.crates[name.as_str()]
+                        .iter()
+                        .all(|dependency| dependency == **name || !remaining.contains(dependency) || in_cycle(name, dependency))
+                })
+                .copied()
+                .collect();
+            let mut layer: Vec<String> = unblocked
+                .iter()
+                .filter(|name| remaining.iter().all(|other| !in_cycle(name, other) || unblocked.contains(other)))
+                .map(|name| (*name).clone())
+                .collect();
+
+            for name in &layer {
+                let _ = remaining.remove(name);
+            }
+
+            layer.sort();
+            layers.push(layer);
+        }
+
+        layers
+    }
+
+    /// Returns the features of a crate that are affected: the `touched` ones (e.g. guarding
+    /// changed code), those enabling an affected optional dependency, and those enabling any of
+    /// these, directly or indirectly.
+    pub fn get_affected_features(&self, crate_name: &str, touched: &BTreeSet<String>, affected: &BTreeSet<String>) -> BTreeSet<String> {
+        let mut result = touched.clone();
+        let Some(features) = self.features.get(crate_name) else {
+            return result;
+        };
+
+        for (name, feature) in features {
+            if feature.crates.iter().any(|dep| affected.contains(dep)) {
+                let _ = result.insert(name.clone());
+            }
+        }
+
+        loop {
+            let enabling: Vec<&String> = features
+                .iter()
+                .filter(|(name, feature)| !result.contains(*name) && feature.features.iter().any(|enabled| result.contains(enabled)))
+                .map(|(name, _)| name)
+                .collect();
+
+            if enabling.is_empty() {
+                break;
+            }
+            result.extend(enabling.into_iter().cloned());
+        }
+
+        result
+    }
+
     pub fn len(&self) -> usize {
         self.crates.len()
     }
@@ -122,6 +318,20 @@ impl Crates {
     pub fn get_all_crate_names(&self) -> Vec<String> {
         self.crates.keys().cloned().collect()
     }
+
+    /// Returns the crate whose manifest lives in a directory of the given name, as found on the
+    /// crate nodes of the file tree. Analyses without directory names keep the name as is.
+    pub fn crate_for_dir(&self, dir_name: &str) -> String {
+        self.dirs
+            .iter()
+            .find(|(_, dir)| *dir == dir_name)
+            .map_or_else(|| dir_name.to_string(), |(crate_name, _)| crate_name.clone())
+    }
+
+    /// Returns the directory name of the crate's manifest, the inverse of [`Self::crate_for_dir`].
+    pub fn dir_for_crate<'a>(&'a self, crate_name: &'a str) -> &'a str {
+        self.dirs.get(crate_name).map_or(crate_name, String::as_str)
+    }
 }
 
 #[cfg(test)]
@@ -129,11 +339,16 @@ mod tests {
     use super::*;
 
     fn make_crates(deps: &[(&str, &[&str])]) -> Crates {
-        let mut crates = HashMap::new();
+        let mut crates = BTreeMap::new();
         for (name, dep_list) in deps {
             let _ = crates.insert((*name).to_string(), dep_list.iter().map(|d| (*d).to_string()).collect());
         }
-        Crates { crates }
+        Crates {
+            crates,
+            kinds: BTreeMap::new(),
+            features: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        }
     }
 
     #[test]
@@ -209,6 +424,157 @@ mod tests {
         assert!(c.get_dependents_transitive("nonexistent").is_none());
     }
 
+    fn names(list: &[&str]) -> BTreeSet<String> {
+        list.iter().map(|n| (*n).to_string()).collect()
+    }
+
+    #[test]
+    fn get_roots_drops_dependencies_of_other_members() {
+        // app -> lib -> core, cli -> core
+        let c = make_crates(&[("app", &["lib"]), ("lib", &["core"]), ("cli", &["core"]), ("core", &[])]);
+        let roots = c.get_roots(&names(&["app", "lib", "cli", "core"]));
+        assert_eq!(roots, names(&["app", "cli"]));
+    }
+
+    #[test]
+    fn get_roots_considers_indirect_dependencies_outside_subset() {
+        // app -> lib -> core, only app and core are in the set
+        let c = make_crates(&[("app", &["lib"]), ("lib", &["core"]), ("core", &[])]);
+        let roots = c.get_roots(&names(&["app", "core"]));
+        assert_eq!(roots, names(&["app"]));
+    }
+
+    #[test]
+    fn get_roots_keeps_crates_in_cycle() {
+        let c = make_crates(&[("a", &["b"]), ("b", &["a"])]);
+        let roots = c.get_roots(&names(&["a", "b"]));
+        assert_eq!(roots, names(&["a", "b"]));
+    }
+
+    #[test]
+    fn get_layers_orders_by_dependency_depth() {
+        // app -> (a, b), a -> c, b -> c
+        let c = make_crates(&[("app", &["a", "b"]), ("a", &["c"]), ("b", &["c"]), ("c", &[])]);
+        let layers = c.get_layers(&names(&["app", "a", "b", "c"]));
+        assert_eq!(layers, vec![vec!["c"], vec!["a", "b"], vec!["app"]]);
+    }
+
+    #[test]
+    fn get_layers_ignores_dependencies_outside_subset() {
+        let c = make_crates(&[("app", &["lib"]), ("lib", &["core"]), ("core", &[])]);
+        let layers = c.get_layers(&names(&["app", "lib", "unknown"]));
+        assert_eq!(layers, vec![vec!["lib"], vec!["app"]]);
+    }
+
+    #[test]
+    fn get_layers_groups_cycles_into_one_layer() {
+        // app -> a <-> b -> core
+        let c = make_crates(&[("app", &["a"]), ("a", &["b"]), ("b", &["a", "core"]), ("core", &[])]);
+        let layers = c.get_layers(&names(&["app", "a", "b", "core"]));
+        assert_eq!(layers, vec![vec!["core"], vec!["a", "b"], vec!["app"]]);
+    }
+
+    #[test]
+    fn get_layers_orders_independent_cycles() {
+        // (a <-> b) -> (c <-> d)
+        let c = make_crates(&[("a", &["b"]), ("b", &["a", "c"]), ("c", &["d"]), ("d", &["c"])]);
+        let layers = c.get_layers(&names(&["a", "b", "c", "d"]));
+        assert_eq!(layers, vec![vec!["c", "d"], vec!["a", "b"]]);
+    }
+
+    fn make_package(name: &str, deps: &[(&str, bool)], features: &[(&str, &[&str])]) -> CargoCrate {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "source": null,
+            "targets": [],
+            "manifest_path": format!("{name}/Cargo.toml"),
+            "dependencies": deps
+                .iter()
+                .map(|(dep, optional)| serde_json::json!({ "name": dep, "source": null, "optional": optional }))
+                .collect::<Vec<_>>(),
+            "features": features.iter().copied().collect::<BTreeMap<_, _>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_resolves_features_to_optional_workspace_crates() {
+        let metadata = CargoMetadata {
+            packages: vec![
+                make_package(
+                    "db",
+                    &[("pg-driver", true), ("sqlite-driver", true), ("core", false)],
+                    &[
+                        ("default", &["sqlite"]),
+                        ("postgres", &["dep:pg-driver", "core/postgres"]),
+                        ("sqlite", &["sqlite-driver/bundled"]),
+                        ("full", &["postgres", "sqlite"]),
+                        ("tls", &["pg-driver?/tls", "sqlite-driver?/tls"]),
+                    ],
+                ),
+                make_package("pg-driver", &[], &[]),
+                make_package("sqlite-driver", &[], &[]),
+                make_package("core", &[], &[]),
+            ],
+            workspace_root: "/ws".into(),
+            target_directory: "/ws/target".into(),
+        };
+
+        let crates = parse(&metadata);
+        let features = &crates.features["db"];
+
+        assert_eq!(features["postgres"].crates, vec!["pg-driver"]);
+        assert_eq!(features["sqlite"].crates, vec!["sqlite-driver"]);
+        assert_eq!(features["full"].features, vec!["postgres", "sqlite"]);
+        assert_eq!(features["tls"], Feature::default());
+        assert_eq!(features["sqlite-driver"].crates, vec!["sqlite-driver"]);
+        assert!(!features.contains_key("pg-driver"));
+        assert!(!features.contains_key("core"));
+    }
+
+    #[test]
+    fn parse_maps_crates_to_their_directories() {
+        let mut core = make_package("core-lib", &[], &[]);
+        core.manifest_path = "crates/core/Cargo.toml".into();
+        let metadata = CargoMetadata {
+            packages: vec![make_package("app", &[("core-lib", false)], &[]), core],
+            workspace_root: "/ws".into(),
+            target_directory: "/ws/target".into(),
+        };
+
+        let crates = parse(&metadata);
+
+        assert_eq!(crates.crate_for_dir("core"), "core-lib");
+        assert_eq!(crates.dir_for_crate("core-lib"), "core");
+        assert_eq!(crates.crate_for_dir("app"), "app");
+        assert_eq!(crates.crate_for_dir("legacy"), "legacy");
+    }
+
+    #[test]
+    fn get_affected_features_follows_optional_deps_and_enabling_features() {
+        let mut c = make_crates(&[("db", &["pg-driver"]), ("pg-driver", &[])]);
+        let feature = |features: &[&str], crates: &[&str]| Feature {
+            features: features.iter().map(ToString::to_string).collect(),
+            crates: crates.iter().map(ToString::to_string).collect(),
+        };
+        let _ = c.features.insert(
+            "db".to_string(),
+            BTreeMap::from([
+                ("postgres".to_string(), feature(&[], &["pg-driver"])),
+                ("tls".to_string(), feature(&[], &[])),
+                ("full".to_string(), feature(&["postgres"], &[])),
+                ("everything".to_string(), feature(&["full"], &[])),
+                ("default".to_string(), feature(&["tls"], &[])),
+            ]),
+        );
+
+        let affected = c.get_affected_features("db", &BTreeSet::new(), &names(&["pg-driver", "db"]));
+        assert_eq!(affected, names(&["everything", "full", "postgres"]));
+
+        let affected = c.get_affected_features("db", &names(&["tls"]), &names(&["db"]));
+        assert_eq!(affected, names(&["default", "tls"]));
+    }
+
     #[test]
     fn len_returns_crate_count() {
         let c = make_crates(&[("a", &[]), ("b", &[]), ("c", &[])]);
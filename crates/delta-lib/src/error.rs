@@ -17,8 +17,21 @@ pub enum Error {
     #[error("Failed to read config file: {0}")]
     ConfigRead(std::io::Error),
 
-    #[error("Failed to parse config file: {0}")]
-    ConfigParse(#[from] toml::de::Error),
+    #[error("Failed to parse config file '{file}': {source}")]
+    ConfigParse {
+        file: String,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("Failed to extend config with '{base}': {message}")]
+    ConfigExtends { base: String, message: String },
+
+    #[error("Invalid config override {origin}: {message}")]
+    ConfigOverride { origin: String, message: String },
+
+    #[error("Invalid [package.metadata.delta] in '{package}': {message}")]
+    PackageConfig { package: String, message: String },
 
     #[error("Failed to read JSON file '{file}': {source}")]
     JsonFileRead {
@@ -34,6 +47,12 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[error("'{file}' uses schema version {found}, but this cargo-delta reads version {expected}; re-create it with a matching version")]
+    SchemaVersion { file: String, found: u32, expected: u32 },
+
+    #[error("Invalid query: {0}")]
+    Query(String),
+
     #[error(transparent)]
     Syn(#[from] syn::Error),
 
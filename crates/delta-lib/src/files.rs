@@ -1,15 +1,18 @@
 use glob::glob;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt, fs,
+    io::Write,
     path::{Path, PathBuf},
 };
+use syn::Token;
+use syn::punctuated::Punctuated;
 use syn::visit::Visit;
 
 use crate::{
-    cargo::{CargoCrate, CargoMetadata},
-    config::{MainConfig, ParserConfig},
+    cargo::{self, CargoCrate, CargoMetadata},
+    config::{AttributeRef, MainConfig, MapRule, ParserConfig, RelativeTo},
     error::Result,
     host::Host,
     utils,
@@ -25,6 +28,10 @@ pub enum FileKind {
     MacroInclude,  // File resolved by include! macro
     FileReference, // File resolved by method calls
     Assume,        // File resolved by assume pattern matching
+    Directory,     // Directory glob (`dir/**`) resolved by dir macros and methods
+    BuildScript,   // Build script generating a file included from OUT_DIR
+    BuildInput,    // File or directory glob (`dir/**`) read by the build script
+    Mapped,        // File assigned to the crate by a `[[map]]` rule
     Unset,         // Unset kind, used for root nodes
 }
 
@@ -39,6 +46,10 @@ impl fmt::Display for FileKind {
             Self::MacroInclude => write!(f, "MacroInclude"),
             Self::FileReference => write!(f, "FileReference"),
             Self::Assume => write!(f, "Assume"),
+            Self::Directory => write!(f, "Directory"),
+            Self::BuildScript => write!(f, "BuildScript"),
+            Self::BuildInput => write!(f, "BuildInput"),
+            Self::Mapped => write!(f, "Mapped"),
             Self::Unset => write!(f, "Unset"),
         }
     }
@@ -49,6 +60,9 @@ impl fmt::Display for FileKind {
 pub struct FileNode {
     pub path: PathBuf,
     pub kind: FileKind,
+    /// `cfg` predicate of the edge from the parent, e.g. `feature = "postgres"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cfg: Option<String>,
     pub children: Vec<FileNode>,
 }
 
@@ -57,10 +71,44 @@ impl FileNode {
         Self {
             path,
             kind,
+            cfg: None,
             children: Vec::new(),
         }
     }
 
+    /// Returns the features named in `cfg` predicates on the way from each crate to the given
+    /// file, keyed by crate. Crates reaching the file only through ungated edges map to no features.
+    pub fn find_features_for_file(&self, target_file: &Path) -> BTreeMap<String, BTreeSet<String>> {
+        fn visit(
+            node: &FileNode,
+            target_file: &Path,
+            current_crate: Option<&str>,
+            features: &BTreeSet<String>,
+            result: &mut BTreeMap<String, BTreeSet<String>>,
+        ) {
+            let current_crate = node.crate_name().or(current_crate);
+
+            let mut features = features.clone();
+            if let Some(cfg) = &node.cfg {
+                features.extend(cfg_features(cfg));
+            }
+
+            if node.claims(target_file)
+                && let Some(crate_name) = current_crate
+            {
+                result.entry(crate_name.to_string()).or_default().extend(features.iter().cloned());
+            }
+
+            for child in &node.children {
+                visit(child, target_file, current_crate, &features, result);
+            }
+        }
+
+        let mut result = BTreeMap::new();
+        visit(self, target_file, None, &BTreeSet::new(), &mut result);
+        result
+    }
+
     pub fn add_child(&mut self, child: Self) {
         if !self.children.iter().any(|existing| existing.path == child.path) {
             self.children.push(child);
@@ -93,15 +141,64 @@ impl FileNode {
         paths
     }
 
+    /// Returns whether the given file is this node, or lies below it for directory globs.
+    pub fn claims(&self, file: &Path) -> bool {
+        if let Some(dir) = self.claimed_directory() {
+            return file.starts_with(dir);
+        }
+        self.path == file
+    }
+
+    pub fn directories(&self) -> Vec<PathBuf> {
+        let mut directories: Vec<PathBuf> = self.children.iter().flat_map(Self::directories).collect();
+        if let Some(dir) = self.claimed_directory() {
+            directories.push(dir.to_path_buf());
+        }
+        directories
+    }
+
+    // Directory globs are recorded as `dir/**`.
+    fn claimed_directory(&self) -> Option<&Path> {
+        if self.path.file_name()? == "**" { self.path.parent() } else { None }
+    }
+
+    fn crate_name(&self) -> Option<&str> {
+        if !matches!(self.kind, FileKind::Crate) {
+            return None;
+        }
+        self.path.parent()?.file_name()?.to_str()
+    }
+
+    pub fn crate_files(&self, crate_name: &str) -> HashSet<PathBuf> {
+        if self.crate_name() == Some(crate_name) {
+            return self.distinct();
+        }
+
+        self.children.iter().flat_map(|child| child.crate_files(crate_name)).collect()
+    }
+
+    pub fn crate_file_counts(&self) -> HashMap<String, usize> {
+        fn visit(node: &FileNode, counts: &mut HashMap<String, usize>) {
+            if let Some(crate_name) = node.crate_name() {
+                *counts.entry(crate_name.to_string()).or_default() += node.distinct().len();
+                return;
+            }
+
+            for child in &node.children {
+                visit(child, counts);
+            }
+        }
+
+        let mut counts = HashMap::new();
+        visit(self, &mut counts);
+        counts
+    }
+
     pub fn find_crates_containing_file(&self, target_file: &PathBuf) -> Vec<String> {
         fn visit(node: &FileNode, target_file: &PathBuf, current_crate: Option<&str>, results: &mut Vec<String>) {
-            let current_crate = if matches!(node.kind, FileKind::Crate) {
-                node.path.parent().and_then(|p| p.file_name()).and_then(|n| n.to_str())
-            } else {
-                current_crate
-            };
+            let current_crate = node.crate_name().or(current_crate);
 
-            if &node.path == target_file
+            if node.claims(target_file)
                 && let Some(crate_name) = current_crate
             {
                 let crate_string = crate_name.to_string();
@@ -121,96 +218,297 @@ impl FileNode {
     }
 }
 
+/// Placeholder for `env!("OUT_DIR")`, files below it are generated by the build script.
+const OUT_DIR: &str = "$OUT_DIR";
+
+/// A `mod` declaration without body, resolved to a file relative to its parent modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ModDecl {
+    parent: Vec<String>,
+    name: String,
+    path: Option<String>,
+    cfg: Option<String>,
+}
+
 struct SourceVisitor<'a> {
-    mods: Vec<String>,
+    mods: Vec<ModDecl>,
     includes: Vec<String>,
-    mod_paths: Vec<(String, String)>,
-    nested_mods: Vec<(Vec<String>, String)>,
+    source_includes: Vec<String>,
     current_path: Vec<String>,
+    cfg_stack: Vec<String>,
     constants: HashMap<String, String>,
     file_refs: Vec<String>,
+    dir_refs: Vec<String>,
+    /// `cfg` predicate of the includes and references, `None` once a path is used ungated.
+    ref_cfgs: HashMap<String, Option<String>>,
+    attribute_refs: Vec<(&'a AttributeRef, String)>,
+    macro_errors: Vec<(String, syn::Error)>,
+    build_inputs: Vec<String>,
+    build_globs: Vec<String>,
     config: &'a ParserConfig,
+    env: &'a HashMap<String, String>,
 }
 
 impl<'a> SourceVisitor<'a> {
-    fn new(config: &'a ParserConfig) -> Self {
+    fn new(config: &'a ParserConfig, env: &'a HashMap<String, String>) -> Self {
         Self {
             mods: Vec::new(),
             includes: Vec::new(),
-            mod_paths: Vec::new(),
-            nested_mods: Vec::new(),
+            source_includes: Vec::new(),
             current_path: Vec::new(),
+            cfg_stack: Vec::new(),
             constants: HashMap::new(),
             file_refs: Vec::new(),
+            dir_refs: Vec::new(),
+            ref_cfgs: HashMap::new(),
+            attribute_refs: Vec::new(),
+            macro_errors: Vec::new(),
+            build_inputs: Vec::new(),
+            build_globs: Vec::new(),
             config,
+            env,
         }
     }
 }
 
 impl<'ast> Visit<'ast> for SourceVisitor<'_> {
+    // `mod` items track their own `cfg` attributes, see `visit_item_mod`.
+    fn visit_item(&mut self, i: &'ast syn::Item) {
+        let attrs = match i {
+            syn::Item::Const(item) => &item.attrs,
+            syn::Item::Enum(item) => &item.attrs,
+            syn::Item::ExternCrate(item) => &item.attrs,
+            syn::Item::Fn(item) => &item.attrs,
+            syn::Item::ForeignMod(item) => &item.attrs,
+            syn::Item::Impl(item) => &item.attrs,
+            syn::Item::Macro(item) => &item.attrs,
+            syn::Item::Static(item) => &item.attrs,
+            syn::Item::Struct(item) => &item.attrs,
+            syn::Item::Trait(item) => &item.attrs,
+            syn::Item::TraitAlias(item) => &item.attrs,
+            syn::Item::Type(item) => &item.attrs,
+            syn::Item::Union(item) => &item.attrs,
+            syn::Item::Use(item) => &item.attrs,
+            _ => &Vec::new(),
+        };
+
+        self.with_cfgs(attrs, |visitor| syn::visit::visit_item(visitor, i));
+    }
+
+    fn visit_impl_item(&mut self, i: &'ast syn::ImplItem) {
+        let attrs = match i {
+            syn::ImplItem::Const(item) => &item.attrs,
+            syn::ImplItem::Fn(item) => &item.attrs,
+            syn::ImplItem::Macro(item) => &item.attrs,
+            syn::ImplItem::Type(item) => &item.attrs,
+            _ => &Vec::new(),
+        };
+
+        self.with_cfgs(attrs, |visitor| syn::visit::visit_impl_item(visitor, i));
+    }
+
+    fn visit_trait_item(&mut self, i: &'ast syn::TraitItem) {
+        let attrs = match i {
+            syn::TraitItem::Const(item) => &item.attrs,
+            syn::TraitItem::Fn(item) => &item.attrs,
+            syn::TraitItem::Macro(item) => &item.attrs,
+            syn::TraitItem::Type(item) => &item.attrs,
+            _ => &Vec::new(),
+        };
+
+        self.with_cfgs(attrs, |visitor| syn::visit::visit_trait_item(visitor, i));
+    }
+
+    fn visit_stmt_macro(&mut self, i: &'ast syn::StmtMacro) {
+        self.with_cfgs(&i.attrs, |visitor| syn::visit::visit_stmt_macro(visitor, i));
+    }
+
     fn visit_item_const(&mut self, i: &'ast syn::ItemConst) {
-        if let syn::Expr::Lit(syn::ExprLit {
-            lit: syn::Lit::Str(lit_str),
-            ..
-        }) = &*i.expr
-        {
-            let _ = self.constants.insert(i.ident.to_string(), lit_str.value());
+        if let Some(value) = self.expr_to_str(&i.expr) {
+            let _ = self.constants.insert(i.ident.to_string(), value);
         }
         syn::visit::visit_item_const(self, i);
     }
 
     fn visit_item_static(&mut self, i: &'ast syn::ItemStatic) {
-        if let syn::Expr::Lit(syn::ExprLit {
-            lit: syn::Lit::Str(lit_str),
-            ..
-        }) = &*i.expr
-        {
-            let _ = self.constants.insert(i.ident.to_string(), lit_str.value());
+        if let Some(value) = self.expr_to_str(&i.expr) {
+            let _ = self.constants.insert(i.ident.to_string(), value);
         }
         syn::visit::visit_item_static(self, i);
     }
 
+    fn visit_local(&mut self, i: &'ast syn::Local) {
+        // Best effort: local bindings are tracked like constants, ignoring scopes.
+        if let syn::Pat::Ident(pat_ident) = &i.pat
+            && let Some(init) = &i.init
+            && let Some(value) = self.expr_to_str(&init.expr)
+        {
+            let _ = self.constants.insert(pat_ident.ident.to_string(), value);
+        }
+        self.with_cfgs(&i.attrs, |visitor| syn::visit::visit_local(visitor, i));
+    }
+
     fn visit_expr_call(&mut self, i: &'ast syn::ExprCall) {
-        if !self.config.file_refs {
-            syn::visit::visit_expr_call(self, i);
-            return;
+        if let syn::Expr::Path(syn::ExprPath { path, .. }) = &*i.func
+            && let Some(last) = path.segments.last()
+            && let Some(first_arg) = i.args.first()
+        {
+            self.record_build_inputs(&last.ident.to_string(), first_arg);
         }
 
         if let syn::Expr::Path(syn::ExprPath { path, .. }) = &*i.func
             && let Some(last) = path.segments.last()
+            && let Some(kind) = self.method_ref_kind(&last.ident.to_string())
+            && let Some(first_arg) = i.args.first()
+            && let Some(path) = self.expr_to_str(first_arg)
         {
-            let method = last.ident.to_string();
-            if !self.config.file_methods.contains(&method) {
-                syn::visit::visit_expr_call(self, i);
-                return;
+            self.push_ref(kind, path);
+        }
+
+        syn::visit::visit_expr_call(self, i);
+    }
+
+    fn visit_expr_method_call(&mut self, i: &'ast syn::ExprMethodCall) {
+        if let Some(first_arg) = i.args.first() {
+            self.record_build_inputs(&i.method.to_string(), first_arg);
+        }
+
+        let Some(kind) = self.method_ref_kind(&i.method.to_string()) else {
+            syn::visit::visit_expr_method_call(self, i);
+            return;
+        };
+
+        // Prefer the whole chain (e.g. `Path::new(a).join(b)`), then the receiver of argument-less
+        // calls such as `path.read_to_string()`, then the first argument.
+        let receiver_path = if i.args.is_empty() {
+            self.expr_to_str(&i.receiver)
+        } else {
+            self.method_call_to_str(i)
+        };
+
+        if let Some(path) = receiver_path {
+            self.push_ref(kind, path);
+
+            // The receiver is part of the resolved path, visiting it would record its prefixes.
+            for arg in &i.args {
+                self.visit_expr(arg);
             }
-            if let Some(first_arg) = i.args.first()
-                && let Some(path) = self.expr_to_str(first_arg)
+            return;
+        }
+
+        if let Some(first_arg) = i.args.first()
+            && let Some(path) = self.expr_to_str(first_arg)
+        {
+            self.push_ref(kind, path);
+        }
+
+        syn::visit::visit_expr_method_call(self, i);
+    }
+
+    fn visit_attribute(&mut self, i: &'ast syn::Attribute) {
+        if self.config.attributes {
+            let config = self.config;
+            for attribute_ref in &config.attribute_refs {
+                if let Some(value) = self.attribute_value(i, attribute_ref) {
+                    self.record_cfg(&value);
+                    self.attribute_refs.push((attribute_ref, value));
+                }
+            }
+        }
+
+        // Visits `#[doc = include_str!(..)]` and similar values as expressions.
+        syn::visit::visit_attribute(self, i);
+    }
+
+    // Handles macros in expression, statement and item position alike, e.g. a top-level `include!`.
+    fn visit_macro(&mut self, i: &'ast syn::Macro) {
+        if let Some(last) = i.path.segments.last() {
+            let macro_name = last.ident.to_string();
+
+            if self.config.dir_refs
+                && self.config.dir_macros.contains(&macro_name)
+                && let Ok(expr) = i.parse_body::<syn::Expr>()
+                && let Some(path) = self.expr_to_str(&expr)
+            {
+                self.record_cfg(&path);
+                self.dir_refs.push(path);
+            }
+
+            if self.config.includes
+                && let Ok(expr) = i.parse_body::<syn::Expr>()
+                && let Some(path) = self.expr_to_str(&expr)
             {
-                self.file_refs.push(path);
+                if self.config.source_include_macros.contains(&macro_name) {
+                    self.record_cfg(&path);
+                    self.source_includes.push(path);
+                } else if self.config.include_macros.contains(&macro_name) {
+                    self.record_cfg(&path);
+                    self.includes.push(path);
+                }
             }
         }
 
-        syn::visit::visit_expr_call(self, i);
+        if self.config.build_inputs
+            && i.path.is_ident("println")
+            && let Ok(args) = i.parse_body_with(Punctuated::<syn::Expr, Token![,]>::parse_terminated)
+            && let Some(line) = self.format_to_str(&args)
+            && let Some(path) = line
+                .strip_prefix("cargo:rerun-if-changed=")
+                .or_else(|| line.strip_prefix("cargo::rerun-if-changed="))
+        {
+            self.build_inputs.push(path.to_string());
+        }
+
+        if self.config.macro_bodies && !i.path.is_ident("macro_rules") {
+            self.visit_macro_body(i);
+        }
+        syn::visit::visit_macro(self, i);
     }
 
     fn visit_item_mod(&mut self, i: &'ast syn::ItemMod) {
         let mod_name = i.ident.to_string();
-        self.current_path.push(mod_name.clone());
+        let parent = self.current_path.clone();
+        let cfgs = extract_cfgs(&i.attrs);
 
         if i.content.is_none() {
-            if let Some(custom_path) = self.extract_path(&i.attrs) {
-                self.mod_paths.push((mod_name, custom_path));
-            } else if self.current_path.len() == 1 {
-                self.mods.push(mod_name);
-            } else {
-                let parent = self.current_path.iter().take(self.current_path.len() - 1).cloned().collect();
+            let base_cfgs: Vec<String> = self.cfg_stack.iter().chain(&cfgs).cloned().collect();
+            let alternatives = self.extract_cfg_attr_paths(&i.attrs);
+            let plain_path = self.extract_path(&i.attrs);
+
+            let mut declare = |path: Option<String>, extra_cfg: Option<String>| {
+                let cfgs = base_cfgs.iter().cloned().chain(extra_cfg).collect();
+                self.mods.push(ModDecl {
+                    parent: parent.clone(),
+                    name: mod_name.clone(),
+                    path,
+                    cfg: cfg_all(cfgs),
+                });
+            };
+
+            // Every `cfg_attr(pred, path = "..")` alternative is a possible edge, the plain
+            // `#[path]` or the default file applies when none of their predicates hold.
+            let fallback_cfg = match alternatives.as_slice() {
+                [] => None,
+                [(predicate, _)] => Some(format!("not({predicate})")),
+                _ => {
+                    let predicates: Vec<&str> = alternatives.iter().map(|(predicate, _)| predicate.as_str()).collect();
+                    Some(format!("not(any({}))", predicates.join(", ")))
+                }
+            };
 
-                self.nested_mods.push((parent, mod_name));
+            for (predicate, path) in &alternatives {
+                declare(Some(path.clone()), Some(predicate.clone()));
             }
+            declare(plain_path, fallback_cfg);
         }
 
+        self.current_path.push(mod_name);
+        let cfg_depth = self.cfg_stack.len();
+        self.cfg_stack.extend(cfgs);
+
         syn::visit::visit_item_mod(self, i);
+
+        self.cfg_stack.truncate(cfg_depth);
         let _ = self.current_path.pop();
     }
 
@@ -228,39 +526,102 @@ impl<'ast> Visit<'ast> for SourceVisitor<'_> {
             if let Some(first_arg) = tokens_str.split(',').next() {
                 let mod_name = first_arg.trim().to_string();
                 if !mod_name.is_empty() {
-                    if self.current_path.is_empty() {
-                        self.mods.push(mod_name);
-                    } else {
-                        let parent = self.current_path.clone();
-                        self.nested_mods.push((parent, mod_name));
-                    }
+                    self.mods.push(ModDecl {
+                        parent: self.current_path.clone(),
+                        name: mod_name,
+                        path: None,
+                        cfg: cfg_all(self.cfg_stack.clone()),
+                    });
                 }
             }
         }
 
         syn::visit::visit_item_macro(self, i);
     }
+}
 
-    fn visit_expr_macro(&mut self, i: &'ast syn::ExprMacro) {
-        let Some(ident) = i.mac.path.get_ident() else {
-            syn::visit::visit_expr_macro(self, i);
+#[derive(Debug, Clone, Copy)]
+enum RefKind {
+    File,
+    Directory,
+}
+
+impl SourceVisitor<'_> {
+    fn method_ref_kind(&self, method: &str) -> Option<RefKind> {
+        if self.config.file_refs && self.config.file_methods.contains(method) {
+            Some(RefKind::File)
+        } else if self.config.dir_refs && self.config.dir_methods.contains(method) {
+            Some(RefKind::Directory)
+        } else {
+            None
+        }
+    }
+
+    // `cc::Build::new().file(..)`, `tonic_build::compile_protos(..)`, `glob(..)` and friends.
+    fn record_build_inputs(&mut self, name: &str, first_arg: &syn::Expr) {
+        if !self.config.build_inputs {
             return;
-        };
+        }
 
-        let macro_name = ident.to_string();
+        if name == "glob" {
+            if let Some(pattern) = self.expr_to_str(first_arg) {
+                self.build_globs.push(pattern);
+            }
+        } else if self.config.build_methods.contains(name) {
+            let paths = self.expr_to_strs(first_arg);
+            self.build_inputs.extend(paths);
+        }
+    }
 
-        if self.config.includes
-            && self.config.include_macros.contains(&macro_name)
-            && let Ok(syn::Lit::Str(lit_str)) = i.mac.parse_body::<syn::Lit>()
-        {
-            self.includes.push(lit_str.value());
+    // Lists of paths, e.g. `&["a.proto", "b.proto"]` or `vec!["a.c"]`.
+    fn expr_to_strs(&self, expr: &syn::Expr) -> Vec<String> {
+        match expr {
+            syn::Expr::Reference(reference) => self.expr_to_strs(&reference.expr),
+            syn::Expr::Array(array) => array.elems.iter().flat_map(|elem| self.expr_to_strs(elem)).collect(),
+            syn::Expr::Macro(mac) if mac.mac.path.is_ident("vec") => mac
+                .mac
+                .parse_body_with(Punctuated::<syn::Expr, Token![,]>::parse_terminated)
+                .map(|elems| elems.iter().flat_map(|elem| self.expr_to_strs(elem)).collect())
+                .unwrap_or_default(),
+            _ => self.expr_to_str(expr).into_iter().collect(),
         }
+    }
 
-        syn::visit::visit_expr_macro(self, i);
+    fn push_ref(&mut self, kind: RefKind, path: String) {
+        self.record_cfg(&path);
+        match kind {
+            RefKind::File => self.file_refs.push(path),
+            RefKind::Directory => self.dir_refs.push(path),
+        }
+    }
+
+    fn with_cfgs(&mut self, attrs: &[syn::Attribute], visit: impl FnOnce(&mut Self)) {
+        let cfg_depth = self.cfg_stack.len();
+        self.cfg_stack.extend(extract_cfgs(attrs));
+        visit(self);
+        self.cfg_stack.truncate(cfg_depth);
+    }
+
+    // A path used under several predicates is gated by any of them.
+    fn record_cfg(&mut self, path: &str) {
+        let cfg = cfg_all(self.cfg_stack.clone());
+        let _ = self
+            .ref_cfgs
+            .entry(path.to_string())
+            .and_modify(|existing| {
+                *existing = match (existing.take(), cfg.clone()) {
+                    (Some(a), Some(b)) if a == b => Some(a),
+                    (Some(a), Some(b)) => Some(format!("any({a}, {b})")),
+                    _ => None,
+                };
+            })
+            .or_insert(cfg);
+    }
+
+    fn ref_cfg(&self, path: &str) -> Option<String> {
+        self.ref_cfgs.get(path).cloned().flatten()
     }
-}
 
-impl SourceVisitor<'_> {
     fn expr_to_str(&self, expr: &syn::Expr) -> Option<String> {
         match expr {
             syn::Expr::Lit(syn::ExprLit {
@@ -268,10 +629,198 @@ impl SourceVisitor<'_> {
                 ..
             }) => Some(lit_str.value()),
             syn::Expr::Path(syn::ExprPath { path, .. }) => self.constants.get(&path.get_ident()?.to_string()).cloned(),
+            syn::Expr::Paren(paren) => self.expr_to_str(&paren.expr),
+            syn::Expr::Reference(reference) => self.expr_to_str(&reference.expr),
+            // `Path::new(x)`, `PathBuf::from(x)`, `String::from(x)`
+            syn::Expr::Call(call) => {
+                let syn::Expr::Path(syn::ExprPath { path, .. }) = &*call.func else {
+                    return None;
+                };
+                let constructor = path.segments.last()?.ident.to_string();
+                match (constructor.as_str(), call.args.len()) {
+                    ("new" | "from", 1) => self.expr_to_str(call.args.first()?),
+                    _ => None,
+                }
+            }
+            syn::Expr::MethodCall(call) => self.method_call_to_str(call),
+            syn::Expr::Macro(expr_macro) => self.macro_to_str(&expr_macro.mac),
             _ => None,
         }
     }
 
+    // `concat!`, `env!`, `stringify!` and literal-only `format!`
+    fn macro_to_str(&self, mac: &syn::Macro) -> Option<String> {
+        let name = mac.path.segments.last()?.ident.to_string();
+        if name == "stringify" {
+            return Some(mac.tokens.to_string());
+        }
+
+        let args = mac
+            .parse_body_with(Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated)
+            .ok()?;
+
+        match name.as_str() {
+            "concat" => args.iter().map(|arg| self.concat_arg_to_str(arg)).collect(),
+            "env" => {
+                let name = self.expr_to_str(args.first()?)?;
+                // `OUT_DIR` is only known while building, keep it as a marker for the build script.
+                let out_dir = (name == "OUT_DIR").then(|| OUT_DIR.to_string());
+                self.env.get(&name).cloned().or(out_dir)
+            }
+            "format" => self.format_to_str(&args),
+            _ => None,
+        }
+    }
+
+    fn concat_arg_to_str(&self, expr: &syn::Expr) -> Option<String> {
+        match expr {
+            syn::Expr::Lit(syn::ExprLit { lit, .. }) => match lit {
+                syn::Lit::Str(lit_str) => Some(lit_str.value()),
+                syn::Lit::Char(lit_char) => Some(lit_char.value().to_string()),
+                syn::Lit::Int(lit_int) => Some(lit_int.base10_digits().to_string()),
+                syn::Lit::Float(lit_float) => Some(lit_float.base10_digits().to_string()),
+                syn::Lit::Bool(lit_bool) => Some(lit_bool.value.to_string()),
+                _ => None,
+            },
+            _ => self.expr_to_str(expr),
+        }
+    }
+
+    // Supports `{}`, `{0}` and `{name}` placeholders without format specs.
+    fn format_to_str(&self, args: &Punctuated<syn::Expr, syn::Token![,]>) -> Option<String> {
+        let mut args = args.iter();
+        let template = self.expr_to_str(args.next()?)?;
+        let values: Vec<String> = args.map(|arg| self.expr_to_str(arg)).collect::<Option<_>>()?;
+
+        let mut result = String::new();
+        let mut next_index = 0;
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    let _ = chars.next();
+                    result.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    let _ = chars.next();
+                    result.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next()? {
+                            '}' => break,
+                            other => placeholder.push(other),
+                        }
+                    }
+
+                    let value = if placeholder.is_empty() {
+                        next_index += 1;
+                        values.get(next_index - 1)?.clone()
+                    } else if let Ok(index) = placeholder.parse::<usize>() {
+                        values.get(index)?.clone()
+                    } else {
+                        self.constants.get(&placeholder)?.clone()
+                    };
+                    result.push_str(&value);
+                }
+                '}' => return None,
+                _ => result.push(c),
+            }
+        }
+
+        Some(result)
+    }
+
+    // `x.join(y)`, `x.to_path_buf()` and friends
+    fn method_call_to_str(&self, call: &syn::ExprMethodCall) -> Option<String> {
+        match (call.method.to_string().as_str(), call.args.len()) {
+            ("join", 1) => {
+                let base = self.expr_to_str(&call.receiver)?;
+                let child = self.expr_to_str(call.args.first()?)?;
+                Some(Path::new(&base).join(child).to_string_lossy().to_string())
+            }
+            ("to_path_buf" | "to_owned" | "to_string" | "as_path" | "as_ref" | "clone" | "into", 0) => self.expr_to_str(&call.receiver),
+            _ => None,
+        }
+    }
+
+    /// Best effort: visits macro bodies that parse as comma-separated expressions (`assert!(..)`,
+    /// `vec![..]`) or as statements. Other bodies, like `lazy_static! { .. }`, are recorded.
+    fn visit_macro_body(&mut self, mac: &syn::Macro) {
+        if mac.tokens.is_empty() {
+            return;
+        }
+
+        let expr_error = match mac.parse_body_with(Punctuated::<syn::Expr, Token![,]>::parse_terminated) {
+            Ok(exprs) => {
+                for expr in &exprs {
+                    self.visit_expr(expr);
+                }
+                return;
+            }
+            Err(error) => error,
+        };
+
+        if let Ok(stmts) = mac.parse_body_with(syn::Block::parse_within) {
+            for stmt in &stmts {
+                self.visit_stmt(stmt);
+            }
+            return;
+        }
+
+        self.macro_errors.push((path_to_string(&mac.path), expr_error));
+    }
+
+    fn attribute_value(&self, attr: &syn::Attribute, attribute_ref: &AttributeRef) -> Option<String> {
+        if path_to_string(attr.path()) != attribute_ref.attribute {
+            return None;
+        }
+
+        let Some(key) = &attribute_ref.key else {
+            return self.expr_to_str(&attr.meta.require_name_value().ok()?.value);
+        };
+
+        let args = attr.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated).ok()?;
+
+        args.iter().find_map(|meta| match meta {
+            syn::Meta::NameValue(name_value) if name_value.path.is_ident(key) => self.expr_to_str(&name_value.value),
+            _ => None,
+        })
+    }
+
+    // `#[cfg_attr(windows, path = "win.rs")]` as `("windows", "win.rs")`
+    fn extract_cfg_attr_paths(&self, attrs: &[syn::Attribute]) -> Vec<(String, String)> {
+        let mut alternatives = Vec::new();
+
+        for attr in attrs {
+            if !attr.path().is_ident("cfg_attr") {
+                continue;
+            }
+
+            let Ok(args) = attr.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated) else {
+                continue;
+            };
+
+            let mut args = args.iter();
+            let Some(predicate) = args.next().and_then(cfg_to_string) else {
+                continue;
+            };
+
+            for meta in args {
+                if let syn::Meta::NameValue(name_value) = meta
+                    && name_value.path.is_ident("path")
+                    && let Some(path) = self.expr_to_str(&name_value.value)
+                {
+                    alternatives.push((predicate.clone(), path));
+                }
+            }
+        }
+
+        alternatives
+    }
+
     fn extract_path(&self, attrs: &[syn::Attribute]) -> Option<String> {
         for attr in attrs {
             if !attr.path().is_ident("path") {
@@ -288,11 +837,85 @@ impl SourceVisitor<'_> {
     }
 }
 
-fn parse_rust<'a>(path: &Path, config: &'a ParserConfig) -> Result<SourceVisitor<'a>> {
+fn extract_cfgs(attrs: &[syn::Attribute]) -> Vec<String> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .filter_map(|attr| cfg_to_string(&attr.parse_args::<syn::Meta>().ok()?))
+        .collect()
+}
+
+/// Formats a `cfg` predicate canonically, e.g. `all(unix, feature = "x")`.
+fn cfg_to_string(meta: &syn::Meta) -> Option<String> {
+    match meta {
+        syn::Meta::Path(path) => Some(path_to_string(path)),
+        syn::Meta::NameValue(name_value) => match &name_value.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(lit_str),
+                ..
+            }) => Some(format!("{} = {:?}", path_to_string(&name_value.path), lit_str.value())),
+            _ => None,
+        },
+        syn::Meta::List(list) => {
+            let args = list.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated).ok()?;
+            let args: Vec<String> = args.iter().map(cfg_to_string).collect::<Option<_>>()?;
+            Some(format!("{}({})", path_to_string(&list.path), args.join(", ")))
+        }
+    }
+}
+
+fn cfg_all(mut cfgs: Vec<String>) -> Option<String> {
+    match cfgs.len() {
+        0 => None,
+        1 => cfgs.pop(),
+        _ => Some(format!("all({})", cfgs.join(", "))),
+    }
+}
+
+/// Features named anywhere in a `cfg` predicate, including negated ones.
+fn cfg_features(cfg: &str) -> BTreeSet<String> {
+    fn visit(meta: &syn::Meta, features: &mut BTreeSet<String>) {
+        match meta {
+            syn::Meta::NameValue(name_value) if name_value.path.is_ident("feature") => {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit_str),
+                    ..
+                }) = &name_value.value
+                {
+                    let _ = features.insert(lit_str.value());
+                }
+            }
+            syn::Meta::List(list) => {
+                if let Ok(args) = list.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated) {
+                    for arg in &args {
+                        visit(arg, features);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut features = BTreeSet::new();
+    if let Ok(meta) = syn::parse_str::<syn::Meta>(cfg) {
+        visit(&meta, &mut features);
+    }
+    features
+}
+
+fn path_to_string(path: &syn::Path) -> String {
+    path.segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+fn parse_rust<'a>(path: &Path, config: &'a ParserConfig, env: &'a HashMap<String, String>) -> Result<SourceVisitor<'a>> {
     let content = fs::read_to_string(path)?;
     let syntax = syn::parse_file(&content)?;
 
-    let mut visitor = SourceVisitor::new(config);
+    let mut visitor = SourceVisitor::new(config, env);
     visitor.visit_file(&syntax);
 
     Ok(visitor)
@@ -323,12 +946,13 @@ fn resolve_mod_files(base: &Path, mods: &[String]) -> Vec<PathBuf> {
             continue;
         };
 
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.extension().and_then(|i| i.to_str()) == Some("rs") {
-                files.push(path);
-            }
-        }
+        let mut dir_files: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|i| i.to_str()) == Some("rs"))
+            .collect();
+        dir_files.sort();
+        files.extend(dir_files);
     }
     files
 }
@@ -339,7 +963,8 @@ fn build_file_node(
     visited: &mut HashSet<PathBuf>,
     workspace_root: Option<&Path>,
     main_config: &MainConfig,
-    crate_name: &str,
+    crate_: &CargoCrate,
+    verbose: bool,
 ) -> FileNode {
     let mut node = FileNode::new(file_path.to_path_buf(), FileKind::Unset);
 
@@ -349,11 +974,22 @@ fn build_file_node(
 
     let _ = visited.insert(file_path.to_path_buf());
 
-    let config = main_config.crate_config(crate_name);
-    let Ok(visitor) = parse_rust(file_path, &config) else {
+    let config = main_config.crate_config(&crate_.name);
+    let env = crate_.env_vars();
+    let Ok(visitor) = parse_rust(file_path, &config, &env) else {
         return node;
     };
 
+    if verbose {
+        for (name, error) in &visitor.macro_errors {
+            let _ = writeln!(
+                host.error(),
+                "Warning: Could not parse body of `{name}!` in {}: {error}",
+                file_path.display()
+            );
+        }
+    }
+
     let Some(base_dir) = file_path.parent() else {
         return node;
     };
@@ -368,57 +1004,156 @@ fn build_file_node(
     };
 
     if config.mods {
-        let mod_files = resolve_mod_files(&actual_base, &visitor.mods);
+        for decl in &visitor.mods {
+            let children: Vec<FileNode> = decl.path.as_ref().map_or_else(
+                || {
+                    let mut parent_dir = actual_base.clone();
+                    for component in &decl.parent {
+                        parent_dir = parent_dir.join(component);
+                    }
 
-        for mod_file in mod_files {
-            let mut child_node = build_file_node(host, &mod_file, visited, workspace_root, main_config, crate_name);
+                    resolve_mod_files(&parent_dir, core::slice::from_ref(&decl.name))
+                        .into_iter()
+                        .map(|mod_file| {
+                            let mut child_node = build_file_node(host, &mod_file, visited, workspace_root, main_config, crate_, verbose);
+                            child_node.kind = FileKind::Module;
+                            child_node
+                        })
+                        .collect()
+                },
+                |custom_path| {
+                    utils::resolve(file_path, custom_path)
+                        .map(|path| FileNode::new(path, FileKind::ModulePath))
+                        .into_iter()
+                        .collect()
+                },
+            );
 
-            child_node.kind = FileKind::Module;
-            node.add_child(child_node);
+            for mut child_node in children {
+                child_node.cfg.clone_from(&decl.cfg);
+                node.add_child(child_node);
+            }
         }
+    }
 
-        for (parent_path, nested_mod_name) in &visitor.nested_mods {
-            let mut parent_dir = actual_base.clone();
-            for component in parent_path {
-                parent_dir = parent_dir.join(component);
-            }
+    let (generated, includes): (Vec<String>, Vec<String>) = visitor.includes.iter().cloned().partition(|path| path.starts_with(OUT_DIR));
+    let (generated_sources, source_includes): (Vec<String>, Vec<String>) =
+        visitor.source_includes.iter().cloned().partition(|path| path.starts_with(OUT_DIR));
 
-            let nested_mod_files = resolve_mod_files(&parent_dir, core::slice::from_ref(nested_mod_name));
+    for include in &includes {
+        for path in utils::resolve_includes(host, file_path, core::slice::from_ref(include)) {
+            node.add_child(reference_node(path, FileKind::MacroInclude, visitor.ref_cfg(include)));
+        }
+    }
 
-            for mod_file in nested_mod_files {
-                let mut child_node = build_file_node(host, &mod_file, visited, workspace_root, main_config, crate_name);
+    for include in &source_includes {
+        for path in utils::resolve_includes(host, file_path, core::slice::from_ref(include)) {
+            let mut child_node = build_file_node(host, &path, visited, workspace_root, main_config, crate_, verbose);
 
-                child_node.kind = FileKind::Module;
-                node.add_child(child_node);
-            }
+            child_node.kind = FileKind::MacroInclude;
+            child_node.cfg = visitor.ref_cfg(include);
+            node.add_child(child_node);
         }
+    }
 
-        for (_, custom_path) in &visitor.mod_paths {
-            if let Some(path) = utils::resolve(file_path, custom_path) {
-                let child = FileNode::new(path, FileKind::ModulePath);
-                node.add_child(child);
-            }
+    if (!generated.is_empty() || !generated_sources.is_empty())
+        && let Some(build_script) = crate_.build_script()
+    {
+        node.add_child(FileNode::new(build_script.to_path_buf(), FileKind::BuildScript));
+    }
+
+    add_reference_nodes(&mut node, &visitor, file_path, crate_.manifest_path.parent(), workspace_root);
+
+    node
+}
+
+/// Adds the files and directories referenced by method calls, macros and attributes.
+fn add_reference_nodes(
+    node: &mut FileNode,
+    visitor: &SourceVisitor<'_>,
+    file_path: &Path,
+    crate_root: Option<&Path>,
+    workspace_root: Option<&Path>,
+) {
+    for file_ref in &visitor.file_refs {
+        let resolved_path = resolve_reference(file_path, crate_root, workspace_root, file_ref);
+
+        if let Some(path) = resolved_path.filter(|path| path.is_file()) {
+            node.add_child(reference_node(path, FileKind::FileReference, visitor.ref_cfg(file_ref)));
         }
     }
 
-    let includes = utils::resolve_includes(host, file_path, &visitor.includes);
+    for dir_ref in &visitor.dir_refs {
+        let resolved_path = resolve_reference(file_path, crate_root, workspace_root, dir_ref);
 
-    for include in includes {
-        node.add_child(FileNode::new(include, FileKind::MacroInclude));
+        if let Some(path) = resolved_path.filter(|path| path.is_dir()) {
+            node.add_child(reference_node(path.join("**"), FileKind::Directory, visitor.ref_cfg(dir_ref)));
+        }
     }
 
-    for file_ref in &visitor.file_refs {
-        let maybe_path = utils::resolve(file_path, file_ref);
-        let resolved_path = maybe_path.or_else(|| utils::resolve_workspace_relative(workspace_root?, file_ref));
+    for (attribute_ref, value) in &visitor.attribute_refs {
+        let reference = attribute_ref
+            .base
+            .as_ref()
+            .map_or_else(|| PathBuf::from(value), |base| Path::new(base).join(value));
+        let reference = reference.to_string_lossy();
+
+        let resolved_path = match attribute_ref.relative_to {
+            RelativeTo::File => utils::resolve(file_path, &reference),
+            RelativeTo::Crate => crate_root.and_then(|root| utils::resolve_workspace_relative(root, &reference)),
+        };
 
-        if let Some(path) = resolved_path {
-            node.add_child(FileNode::new(path, FileKind::FileReference));
+        let cfg = visitor.ref_cfg(value);
+        match resolved_path {
+            Some(path) if attribute_ref.directory && path.is_dir() => {
+                node.add_child(reference_node(path.join("**"), FileKind::Directory, cfg));
+            }
+            Some(path) if !attribute_ref.directory && path.is_file() => node.add_child(reference_node(path, FileKind::FileReference, cfg)),
+            _ => {}
         }
     }
+}
 
+fn reference_node(path: PathBuf, kind: FileKind, cfg: Option<String>) -> FileNode {
+    let mut node = FileNode::new(path, kind);
+    node.cfg = cfg;
     node
 }
 
+fn resolve_reference(file_path: &Path, crate_root: Option<&Path>, workspace_root: Option<&Path>, reference: &str) -> Option<PathBuf> {
+    utils::resolve(file_path, reference)
+        .or_else(|| utils::resolve_workspace_relative(crate_root?, reference))
+        .or_else(|| utils::resolve_workspace_relative(workspace_root?, reference))
+}
+
+/// Finds the inputs of a build script: `rerun-if-changed` paths, files passed to code generators
+/// and compilers, and files matched by `glob` patterns. Paths are relative to the crate root, like
+/// the working directory of the build script.
+fn find_build_inputs(build_script: &Path, crate_root: &Path, config: &ParserConfig, env: &HashMap<String, String>) -> Vec<PathBuf> {
+    let Ok(visitor) = parse_rust(build_script, config, env) else {
+        return Vec::new();
+    };
+
+    let mut inputs = resolve_build_inputs(crate_root, &visitor.build_inputs);
+
+    for pattern in &visitor.build_globs {
+        if let Ok(paths) = glob(&crate_root.join(pattern).to_string_lossy()) {
+            inputs.extend(paths.flatten().filter(|path| path.is_file()));
+        }
+    }
+
+    inputs
+}
+
+/// Resolves `rerun-if-changed` style paths, where a directory stands for every file below it.
+fn resolve_build_inputs(crate_root: &Path, inputs: &[String]) -> Vec<PathBuf> {
+    inputs
+        .iter()
+        .filter_map(|input| utils::resolve_workspace_relative(crate_root, input))
+        .map(|path| if path.is_dir() { path.join("**") } else { path })
+        .collect()
+}
+
 fn find_assume_files(crate_root: &Path, patterns: &HashSet<String>) -> Vec<PathBuf> {
     let mut found_files = Vec::new();
     for pattern in patterns {
@@ -437,8 +1172,35 @@ fn find_assume_files(crate_root: &Path, patterns: &HashSet<String>) -> Vec<PathB
     found_files
 }
 
-pub fn build_tree(host: &mut impl Host, metadata: &CargoMetadata, crates: &[&CargoCrate], config: &MainConfig) -> FileNode {
+/// Files matched by `[[map]]` rules, keyed by the crates they are mapped to.
+fn find_mapped_files<'a>(git_root: &Path, rules: &'a [MapRule]) -> HashMap<&'a str, BTreeSet<PathBuf>> {
+    let mut mapped: HashMap<&str, BTreeSet<PathBuf>> = HashMap::new();
+
+    for rule in rules {
+        let Ok(paths) = glob(&git_root.join(&rule.pattern).to_string_lossy()) else {
+            continue;
+        };
+        let files: Vec<PathBuf> = paths.flatten().filter(|path| path.is_file()).collect();
+
+        for crate_name in &rule.crates {
+            mapped.entry(crate_name).or_default().extend(files.iter().cloned());
+        }
+    }
+
+    mapped
+}
+
+pub fn build_tree(
+    host: &mut impl Host,
+    metadata: &CargoMetadata,
+    crates: &[&CargoCrate],
+    config: &MainConfig,
+    git_root: &Path,
+    target_dir: Option<&Path>,
+    verbose: bool,
+) -> FileNode {
     let mut visited = HashSet::new();
+    let mut mapped_files = find_mapped_files(git_root, &config.map);
 
     let root_path = metadata.workspace_root.join("Cargo.toml");
     let root_kind = FileKind::Workspace;
@@ -457,7 +1219,8 @@ pub fn build_tree(host: &mut impl Host, metadata: &CargoMetadata, crates: &[&Car
                 &mut visited,
                 Some(&metadata.workspace_root),
                 config,
-                &crate_.name,
+                crate_,
+                verbose,
             );
 
             for child in source_tree.children {
@@ -468,6 +1231,26 @@ pub fn build_tree(host: &mut impl Host, metadata: &CargoMetadata, crates: &[&Car
         }
 
         let parser_config = config.crate_config(&crate_.name);
+
+        if let Some(crate_root) = crate_.manifest_path.parent() {
+            let mut build_inputs = BTreeSet::new();
+
+            if parser_config.build_inputs
+                && let Some(build_script) = crate_.build_script()
+            {
+                build_inputs.extend(find_build_inputs(build_script, crate_root, &parser_config, &crate_.env_vars()));
+            }
+
+            if let Some(target_dir) = target_dir {
+                let emitted = cargo::build_script_rerun_paths(target_dir, &crate_.name);
+                build_inputs.extend(resolve_build_inputs(crate_root, &emitted));
+            }
+
+            for input in build_inputs {
+                node.add_child(FileNode::new(input, FileKind::BuildInput));
+            }
+        }
+
         if parser_config.assume
             && !parser_config.assume_patterns.is_empty()
             && let Some(crate_root) = crate_.manifest_path.parent()
@@ -481,6 +1264,10 @@ pub fn build_tree(host: &mut impl Host, metadata: &CargoMetadata, crates: &[&Car
             }
         }
 
+        for mapped_file in mapped_files.remove(crate_.name.as_str()).unwrap_or_default() {
+            node.add_child(FileNode::new(mapped_file, FileKind::Mapped));
+        }
+
         root_node.add_child(node);
     }
 
@@ -490,6 +1277,8 @@ pub fn build_tree(host: &mut impl Host, metadata: &CargoMetadata, crates: &[&Car
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cargo::CargoTarget;
+    use crate::test_helpers::TestHost;
 
     #[test]
     fn new_file_node_has_no_children() {
@@ -582,6 +1371,20 @@ mod tests {
         assert_eq!(crates, vec!["my-crate"]);
     }
 
+    #[test]
+    fn crate_files_collects_files_of_named_crate() {
+        let mut root = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let mut crate_node = FileNode::new(PathBuf::from("my-crate/Cargo.toml"), FileKind::Crate);
+        crate_node.add_child(FileNode::new(PathBuf::from("my-crate/src/lib.rs"), FileKind::Target));
+        root.add_child(crate_node);
+        root.add_child(FileNode::new(PathBuf::from("other/Cargo.toml"), FileKind::Crate));
+
+        let files = root.crate_files("my-crate");
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&PathBuf::from("my-crate/src/lib.rs")));
+        assert!(root.crate_files("missing").is_empty());
+    }
+
     #[test]
     fn find_crates_containing_file_returns_empty_for_no_match() {
         let root = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
@@ -590,6 +1393,579 @@ mod tests {
         assert!(crates.is_empty());
     }
 
+    fn visit_source<'a>(source: &str, config: &'a ParserConfig, env: &'a HashMap<String, String>) -> SourceVisitor<'a> {
+        let syntax = syn::parse_file(source).unwrap();
+        let mut visitor = SourceVisitor::new(config, env);
+        visitor.visit_file(&syntax);
+        visitor
+    }
+
+    #[test]
+    fn visitor_detects_file_method_calls() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            fn f(dir: &Path, base: PathBuf) {
+                let _ = dir.join("fixtures/a.json");
+                let _ = File::open("data.txt");
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.file_refs, vec!["fixtures/a.json", "data.txt"]);
+    }
+
+    #[test]
+    fn visitor_evaluates_path_chains() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            const ROOT: &str = "tests";
+            fn f() {
+                let data = Path::new(ROOT).join("data");
+                let _ = data.join("x.json").read_to_string();
+                let _ = PathBuf::from("a").join("b").join("c.txt");
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.file_refs, vec!["tests/data", "tests/data/x.json", "a/b/c.txt"]);
+    }
+
+    #[test]
+    fn visitor_uses_configured_file_methods() {
+        let mut config = ParserConfig::default();
+        let _ = config.file_methods.insert("with_config_file".to_string());
+        let env = HashMap::new();
+        let visitor = visit_source(r#"fn f() { Builder::new().with_config_file("app.toml").build(); }"#, &config, &env);
+        assert_eq!(visitor.file_refs, vec!["app.toml"]);
+
+        config.file_refs = false;
+        let visitor = visit_source(r#"fn f() { Builder::new().with_config_file("app.toml").build(); }"#, &config, &env);
+        assert!(visitor.file_refs.is_empty());
+    }
+
+    #[test]
+    #[expect(clippy::literal_string_with_formatting_args, reason = "parsed Rust source, not a format string")]
+    fn visitor_evaluates_constant_macros() {
+        let config = ParserConfig::default();
+        let env = HashMap::from([("CARGO_MANIFEST_DIR".to_string(), "/ws/my-crate".to_string())]);
+        let visitor = visit_source(
+            r#"
+            const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+            fn f() {
+                let _ = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/x.json"));
+                let _ = File::open(format!("{}/fixtures/{0}.{ext}", env!("CARGO_MANIFEST_DIR")));
+                let _ = File::open(format!("{DATA}/y.json"));
+                let _ = File::open(concat!(stringify!(name), ".", 1));
+                let _ = File::open(format!("{:?}", DATA));
+                let _ = File::open(env!("UNKNOWN_VAR"));
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.includes, vec!["/ws/my-crate/tests/data/x.json"]);
+        assert_eq!(visitor.file_refs, vec!["/ws/my-crate/tests/data/y.json", "name.1"]);
+    }
+
+    #[test]
+    fn visitor_evaluates_path_attribute_macros() {
+        let config = ParserConfig::default();
+        let env = HashMap::from([("CARGO_MANIFEST_DIR".to_string(), "/ws".to_string())]);
+        let visitor = visit_source(
+            r#"#[path = concat!(env!("CARGO_MANIFEST_DIR"), "/gen.rs")] mod generated;"#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.mods.len(), 1);
+        assert_eq!(visitor.mods[0].name, "generated");
+        assert_eq!(visitor.mods[0].path.as_deref(), Some("/ws/gen.rs"));
+    }
+
+    fn mod_decl(parent: &[&str], name: &str, path: Option<&str>, cfg: Option<&str>) -> ModDecl {
+        ModDecl {
+            parent: parent.iter().map(ToString::to_string).collect(),
+            name: name.to_string(),
+            path: path.map(ToString::to_string),
+            cfg: cfg.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn visitor_annotates_mods_with_cfg() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            mod common;
+            #[cfg(feature = "postgres")]
+            mod pg;
+            #[cfg(all(unix, not(feature = "sync")))]
+            mod backend {
+                #[cfg(test)]
+                mod tests;
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(
+            visitor.mods,
+            vec![
+                mod_decl(&[], "common", None, None),
+                mod_decl(&[], "pg", None, Some(r#"feature = "postgres""#)),
+                mod_decl(&["backend"], "tests", None, Some(r#"all(all(unix, not(feature = "sync")), test)"#)),
+            ]
+        );
+    }
+
+    #[test]
+    fn visitor_resolves_every_cfg_attr_path() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            #[cfg_attr(windows, path = "win.rs")]
+            #[cfg_attr(unix, path = "unix.rs")]
+            mod os;
+            #[cfg_attr(feature = "mock", path = "mock.rs")]
+            #[path = "real.rs"]
+            mod client;
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(
+            visitor.mods,
+            vec![
+                mod_decl(&[], "os", Some("win.rs"), Some("windows")),
+                mod_decl(&[], "os", Some("unix.rs"), Some("unix")),
+                mod_decl(&[], "os", None, Some("not(any(windows, unix))")),
+                mod_decl(&[], "client", Some("mock.rs"), Some(r#"feature = "mock""#)),
+                mod_decl(&[], "client", Some("real.rs"), Some(r#"not(feature = "mock")"#)),
+            ]
+        );
+    }
+
+    #[test]
+    fn visitor_annotates_references_with_cfg() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            #[cfg(feature = "x")]
+            const SCHEMA: &str = include_str!("schema.sql");
+            #[cfg(unix)]
+            impl Loader {
+                #[cfg(feature = "y")]
+                fn load() {
+                    let _ = fs::read("data.json");
+                    let _ = fs::read("shared.json");
+                }
+            }
+            fn f() {
+                let _ = fs::read("shared.json");
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.ref_cfg("schema.sql").as_deref(), Some(r#"feature = "x""#));
+        assert_eq!(visitor.ref_cfg("data.json").as_deref(), Some(r#"all(unix, feature = "y")"#));
+        assert_eq!(visitor.ref_cfg("shared.json"), None);
+    }
+
+    #[test]
+    fn cfg_features_collects_named_features() {
+        assert_eq!(
+            cfg_features(r#"all(unix, any(feature = "a", not(feature = "b")), test)"#),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+        assert!(cfg_features("windows").is_empty());
+    }
+
+    #[test]
+    fn find_features_for_file_follows_gated_edges() {
+        let mut root = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let mut crate_node = FileNode::new(PathBuf::from("db/Cargo.toml"), FileKind::Crate);
+        let mut lib = FileNode::new(PathBuf::from("db/src/lib.rs"), FileKind::Target);
+        let mut pg = FileNode::new(PathBuf::from("db/src/pg.rs"), FileKind::Module);
+        pg.cfg = Some(r#"feature = "postgres""#.to_string());
+        let mut pool = FileNode::new(PathBuf::from("db/src/pg/pool.rs"), FileKind::Module);
+        pool.cfg = Some(r#"feature = "pool""#.to_string());
+        pg.add_child(pool);
+        lib.add_child(pg);
+        crate_node.add_child(lib);
+        root.add_child(crate_node);
+
+        let features = root.find_features_for_file(Path::new("db/src/pg/pool.rs"));
+        assert_eq!(features["db"], BTreeSet::from(["pool".to_string(), "postgres".to_string()]));
+        assert!(root.find_features_for_file(Path::new("db/src/lib.rs"))["db"].is_empty());
+    }
+
+    #[test]
+    fn visitor_detects_directory_references() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+            static ASSETS: Dir = include_dir!("assets");
+            fn f(base: &Path) {
+                let _ = fs::read_dir("fixtures");
+                let _ = base.join("snapshots").read_dir();
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.dir_refs, vec!["./migrations", "assets", "fixtures"]);
+        assert!(visitor.file_refs.contains(&"snapshots".to_string()));
+    }
+
+    #[test]
+    fn visitor_scans_macro_bodies() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            fn f() {
+                assert!(File::open("a.txt").is_ok(), "missing {}", "a.txt");
+                let _ = vec![fs::read_to_string("b.txt"), fs::read_to_string("c.txt")];
+                my_macro! {
+                    let data = Path::new("d.json");
+                    data.read_to_string()
+                }
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.file_refs, vec!["a.txt", "b.txt", "c.txt", "d.json"]);
+        assert!(visitor.macro_errors.is_empty());
+    }
+
+    #[test]
+    fn visitor_records_unparsable_macro_bodies() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            macro_rules! noop { ($($t:tt)*) => {}; }
+            lazy_static::lazy_static! {
+                static ref DATA: String = fs::read_to_string("data.txt").unwrap();
+            }
+            "#,
+            &config,
+            &env,
+        );
+        let names: Vec<&str> = visitor.macro_errors.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["lazy_static::lazy_static"]);
+    }
+
+    #[test]
+    fn visitor_skips_macro_bodies_when_disabled() {
+        let config: ParserConfig = toml::from_str("macro_bodies = false").unwrap();
+        let env = HashMap::new();
+        let visitor = visit_source(r#"fn f() { assert!(File::open("a.txt").is_ok()); }"#, &config, &env);
+        assert!(visitor.file_refs.is_empty());
+    }
+
+    #[test]
+    fn visitor_separates_source_includes() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            include!("generated.rs");
+            include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+            const SCHEMA: &str = include_str!("schema.sql");
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.source_includes, vec!["generated.rs", "$OUT_DIR/bindings.rs"]);
+        assert_eq!(visitor.includes, vec!["schema.sql"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn build_file_node_annotates_gated_includes() {
+        let root = std::env::temp_dir().join("cargo_delta_test_gated_includes");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(
+            root.join("src/lib.rs"),
+            "#[cfg(feature = \"x\")]\nconst SCHEMA: &str = include_str!(\"schema.sql\");",
+        )
+        .unwrap();
+        fs::write(root.join("src/schema.sql"), "").unwrap();
+
+        let crate_ = CargoCrate {
+            name: "db".to_string(),
+            source: None,
+            targets: vec![],
+            manifest_path: root.join("Cargo.toml"),
+            dependencies: vec![],
+            features: BTreeMap::new(),
+            metadata: None,
+        };
+        let mut host = TestHost::new();
+        let lib = build_file_node(
+            &mut host,
+            &root.join("src/lib.rs"),
+            &mut HashSet::new(),
+            None,
+            &MainConfig::default(),
+            &crate_,
+            false,
+        );
+        let _ = fs::remove_dir_all(&root);
+
+        // Crate nodes are named after their directory.
+        let mut crate_node = FileNode::new(PathBuf::from("db/Cargo.toml"), FileKind::Crate);
+        crate_node.add_child(lib);
+        let features = crate_node.find_features_for_file(&root.join("src/schema.sql"));
+        assert_eq!(features["db"], BTreeSet::from(["x".to_string()]));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn build_file_node_parses_source_includes() {
+        let root = std::env::temp_dir().join("cargo_delta_test_source_includes");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(
+            root.join("src/lib.rs"),
+            "include!(\"tables.rs\");\ninclude!(concat!(env!(\"OUT_DIR\"), \"/gen.rs\"));",
+        )
+        .unwrap();
+        fs::write(root.join("src/tables.rs"), "mod users;").unwrap();
+        fs::write(root.join("src/users.rs"), "").unwrap();
+        fs::write(root.join("build.rs"), "fn main() {}").unwrap();
+
+        let crate_ = CargoCrate {
+            name: "gen".to_string(),
+            source: None,
+            targets: vec![CargoTarget {
+                name: "build-script-build".to_string(),
+                kind: vec!["custom-build".to_string()],
+                src_path: root.join("build.rs"),
+            }],
+            manifest_path: root.join("Cargo.toml"),
+            dependencies: vec![],
+            features: BTreeMap::new(),
+            metadata: None,
+        };
+        let mut host = TestHost::new();
+        let node = build_file_node(
+            &mut host,
+            &root.join("src/lib.rs"),
+            &mut HashSet::new(),
+            None,
+            &MainConfig::default(),
+            &crate_,
+            false,
+        );
+        let _ = fs::remove_dir_all(&root);
+
+        let include = &node.children[0];
+        assert_eq!(include.kind, FileKind::MacroInclude);
+        assert!(include.path.ends_with("src/tables.rs"));
+        assert!(include.children[0].path.ends_with("src/users.rs"));
+        assert_eq!(node.children[1].kind, FileKind::BuildScript);
+        assert!(node.children[1].path.ends_with("build.rs"));
+    }
+
+    #[test]
+    fn visitor_detects_build_inputs() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            const SCHEMA: &str = "schema.sql";
+            fn main() {
+                println!("cargo:rerun-if-changed=build.rs");
+                println!("cargo::rerun-if-changed={}", SCHEMA);
+                println!("cargo:rerun-if-env-changed=PROTOC");
+                tonic_build::configure().compile_protos(&["proto/a.proto", "proto/b.proto"], &["proto"]).unwrap();
+                prost_build::compile_protos(&vec!["proto/c.proto"], &["proto"]).unwrap();
+                cc::Build::new().file("src/native.c").compile("native");
+                for entry in glob::glob("migrations/*.sql").unwrap() {}
+            }
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(
+            visitor.build_inputs,
+            vec![
+                "build.rs",
+                "schema.sql",
+                "proto/a.proto",
+                "proto/b.proto",
+                "proto/c.proto",
+                "src/native.c"
+            ]
+        );
+        assert_eq!(visitor.build_globs, vec!["migrations/*.sql"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn find_build_inputs_resolves_relative_to_crate_root() {
+        let root = std::env::temp_dir().join("cargo_delta_test_build_inputs");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("proto")).unwrap();
+        fs::create_dir_all(root.join("migrations")).unwrap();
+        fs::write(root.join("proto/api.proto"), "").unwrap();
+        fs::write(root.join("migrations/001.sql"), "").unwrap();
+        fs::write(
+            root.join("build.rs"),
+            r#"fn main() {
+                println!("cargo:rerun-if-changed=proto");
+                println!("cargo:rerun-if-changed=missing.h");
+                tonic_build::compile_protos("proto/api.proto").unwrap();
+                let _ = glob::glob("migrations/*.sql");
+            }"#,
+        )
+        .unwrap();
+
+        let inputs = find_build_inputs(&root.join("build.rs"), &root, &ParserConfig::default(), &HashMap::new());
+        let _ = fs::remove_dir_all(&root);
+
+        let paths: Vec<PathBuf> = inputs
+            .iter()
+            .map(|input| input.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("proto/**"),
+                PathBuf::from("proto/api.proto"),
+                PathBuf::from("migrations/001.sql")
+            ]
+        );
+        let node = FileNode::new(inputs[0].clone(), FileKind::BuildInput);
+        assert!(node.claims(&root.join("proto/new.proto")));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn find_mapped_files_globs_relative_to_git_root() {
+        let root = std::env::temp_dir().join("cargo_delta_test_mapped_files");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("schemas/v1")).unwrap();
+        fs::write(root.join("schemas/v1/user.json"), "").unwrap();
+        fs::write(root.join("schemas/README.md"), "").unwrap();
+        let rules = vec![MapRule {
+            pattern: "schemas/**/*.json".to_string(),
+            crates: vec!["api".to_string(), "web".to_string()],
+        }];
+
+        let mapped = find_mapped_files(&root, &rules);
+        let _ = fs::remove_dir_all(&root);
+
+        let expected = BTreeSet::from([root.join("schemas/v1/user.json")]);
+        assert_eq!(mapped["api"], expected);
+        assert_eq!(mapped["web"], expected);
+    }
+
+    #[test]
+    fn visitor_detects_doc_includes() {
+        let config = ParserConfig::default();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            #![doc = include_str!("../README.md")]
+            #[doc = include_str!(concat!("docs/", "api.md"))]
+            pub struct Api;
+            "#,
+            &config,
+            &env,
+        );
+        assert_eq!(visitor.includes, vec!["../README.md", "docs/api.md"]);
+    }
+
+    #[test]
+    fn visitor_detects_attribute_references() {
+        let config: ParserConfig = toml::from_str(
+            r#"
+            attribute_refs = [
+                { attribute = "template", key = "path", base = "templates" },
+                { attribute = "folder", directory = true },
+                { attribute = "serde_with::file", key = "path" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let env = HashMap::new();
+        let visitor = visit_source(
+            r#"
+            #[derive(Template)]
+            #[template(path = "index.html", escape = "none")]
+            struct Index;
+
+            #[derive(RustEmbed)]
+            #[folder = "static/"]
+            struct Assets;
+
+            #[serde_with::file(format = json, path = "schema.json")]
+            struct Schema;
+
+            #[template(source = "inline")]
+            struct Inline;
+            "#,
+            &config,
+            &env,
+        );
+        let values: Vec<(&str, &str)> = visitor
+            .attribute_refs
+            .iter()
+            .map(|(attribute_ref, value)| (attribute_ref.attribute.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("template", "index.html"),
+                ("folder", "static/"),
+                ("serde_with::file", "schema.json")
+            ]
+        );
+    }
+
+    #[test]
+    fn visitor_ignores_attributes_when_disabled() {
+        let config: ParserConfig = toml::from_str(
+            r#"
+            attributes = false
+            attribute_refs = [{ attribute = "folder" }]
+            "#,
+        )
+        .unwrap();
+        let env = HashMap::new();
+        let visitor = visit_source("#[folder = \"static/\"] struct Assets;", &config, &env);
+        assert!(visitor.attribute_refs.is_empty());
+    }
+
+    #[test]
+    fn directory_node_claims_files_below_it() {
+        let mut root = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let mut crate_node = FileNode::new(PathBuf::from("db/Cargo.toml"), FileKind::Crate);
+        crate_node.add_child(FileNode::new(PathBuf::from("db/migrations/**"), FileKind::Directory));
+        root.add_child(crate_node);
+
+        let crates = root.find_crates_containing_file(&PathBuf::from("db/migrations/2024/001_new.sql"));
+        assert_eq!(crates, vec!["db"]);
+        assert!(root.find_crates_containing_file(&PathBuf::from("db/migrations.sql")).is_empty());
+        assert_eq!(root.directories(), vec![PathBuf::from("db/migrations")]);
+    }
+
     #[test]
     fn file_kind_display() {
         assert_eq!(FileKind::Workspace.to_string(), "Workspace");
@@ -1,5 +1,6 @@
+use std::ffi::OsString;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Output;
 
 /// Abstract the host environment to enable testing.
@@ -15,4 +16,10 @@ pub trait Host: Send + Sync {
 
     /// Run an external command and return its output.
     fn run_command(&mut self, command: &str, args: &[&str], working_dir: Option<&Path>) -> io::Result<Output>;
+
+    /// Environment variables of the process, which are not necessarily valid Unicode.
+    fn env_vars(&mut self) -> Vec<(OsString, OsString)>;
+
+    /// Working directory of the process.
+    fn current_dir(&mut self) -> io::Result<PathBuf>;
 }
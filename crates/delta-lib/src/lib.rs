@@ -6,17 +6,20 @@
 
 use clap::builder::Styles;
 use clap::builder::styling::{AnsiColor, Effects};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use core::num::NonZeroUsize;
+use normpath::PathExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 
-use crate::config::MainConfig;
+use crate::config::{ConfigOverride, ConfigSource, MainConfig, TripWire};
 use crate::crates::Crates;
 use crate::files::FileNode;
 use crate::git::GitDiff;
+use crate::schema::SCHEMA_VERSION;
 
 mod cargo;
 mod config;
@@ -25,6 +28,9 @@ mod error;
 mod files;
 mod git;
 mod host;
+mod query;
+mod schema;
+mod shards;
 mod utils;
 
 pub use host::Host;
@@ -53,10 +59,15 @@ enum CargoSubcommand {
 #[command(name = "cargo-delta", author, version, long_about = None, display_name = "cargo-delta")]
 #[command(about = "Identify impacted crates from git changes")]
 struct Args {
-    /// Path to configuration file (defaults to `delta.toml`)
+    /// Path to configuration file (defaults to `delta.toml`, `.cargo/delta.toml` or
+    /// `[workspace.metadata.delta]` of the workspace)
     #[arg(short = 'c', long, value_name = "PATH")]
     config: Option<PathBuf>,
 
+    /// Override a config setting, e.g. `git.remote_branch=origin/release` (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -67,6 +78,12 @@ enum Commands {
     Run(RunCommand),
     /// Analyze current workspace and produce JSON output
     Analyze(AnalyzeCommand),
+    /// Evaluate a query over the crate and file graph of an analysis
+    Query(QueryCommand),
+    /// Print the JSON Schema of an output document
+    Schema(SchemaCommand),
+    /// Inspect the configuration
+    Config(ConfigCommand),
 }
 
 #[derive(Parser)]
@@ -77,34 +94,163 @@ struct RunCommand {
     /// Current workspace analysis JSON file (e.g., from feature branch)
     #[arg(long, value_name = "PATH")]
     current: PathBuf,
+    /// Include required crates grouped into topologically ordered build layers
+    #[arg(long)]
+    layers: bool,
+    /// Split affected crates into N shards of balanced weight
+    #[arg(long, value_name = "N")]
+    shards: Option<NonZeroUsize>,
+    /// JSON file mapping crate names to their cost (e.g., past test durations) for sharding
+    #[arg(long, value_name = "PATH", requires = "shards")]
+    costs: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct AnalyzeCommand {
+    /// Read `rerun-if-changed` paths from build script outputs (defaults to cargo's target directory)
+    #[arg(long, value_name = "PATH")]
+    #[expect(clippy::option_option, reason = "clap distinguishes an absent flag from a flag without value")]
+    target_dir: Option<Option<PathBuf>>,
+    /// Report macro bodies that could not be scanned for file references
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Parser)]
+struct QueryCommand {
+    /// Query expression (e.g., `rdeps(my-crate) - kind(bin, all())`)
+    #[arg(value_name = "EXPR")]
+    expr: String,
+    /// Workspace analysis JSON file to query
+    #[arg(long, value_name = "PATH")]
+    analysis: PathBuf,
+    /// Run result JSON file that `impacted()` refers to
+    #[arg(long, value_name = "PATH")]
+    impact: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct SchemaCommand {
+    /// Document to print the schema for
+    #[arg(value_enum)]
+    document: SchemaDocument,
+}
+
+#[derive(Parser)]
+struct ConfigCommand {
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Validate the configuration, exiting with an error on any problem
+    Check,
+    /// Print the effective configuration and where each value came from
+    Show(ConfigShowCommand),
 }
 
 #[derive(Parser)]
-struct AnalyzeCommand;
+struct ConfigShowCommand {
+    /// Show the parser settings of this crate, after its overrides
+    #[arg(long = "crate", value_name = "NAME")]
+    crate_name: Option<String>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+    format: ConfigFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SchemaDocument {
+    /// Workspace analysis produced by `analyze`
+    Analysis,
+    /// Impacted crates produced by `run`
+    Impact,
+}
 
 #[doc(hidden)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Impact {
+    #[serde(default = "schema::legacy_schema_version")]
+    pub schema_version: u32,
     #[serde(rename = "Modified")]
-    pub modified: HashSet<String>,
+    pub modified: BTreeSet<String>,
     #[serde(rename = "Affected")]
-    pub affected: HashSet<String>,
+    pub affected: BTreeSet<String>,
     #[serde(rename = "Required")]
-    pub required: HashSet<String>,
+    pub required: BTreeSet<String>,
+    /// Missing from cargo-delta 0.2 results, derived from `affected` when they are migrated.
+    #[serde(rename = "Roots", default)]
+    pub roots: BTreeSet<String>,
+    #[serde(rename = "Groups", default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub groups: BTreeSet<String>,
+    #[serde(rename = "Features", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, BTreeSet<String>>,
+    #[serde(rename = "Layers", default, skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<Vec<String>>>,
+    #[serde(rename = "Shards", default, skip_serializing_if = "Option::is_none")]
+    pub shards: Option<Vec<shards::Shard>>,
 }
 
 #[doc(hidden)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct WorkspaceTree {
+    #[serde(default = "schema::legacy_schema_version")]
+    pub schema_version: u32,
     pub files: FileNode,
     pub crates: Crates,
+    /// `[profile]` of the workspace `Cargo.toml`, empty without one. Missing from analyses written
+    /// before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<serde_json::Value>,
+    /// Files of the delta config, relative to the git root.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub config_files: Vec<PathBuf>,
+    /// `[workspace.metadata.delta]` of the workspace `Cargo.toml`, empty without one. Missing from
+    /// analyses written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_metadata: Option<serde_json::Value>,
+}
+
+impl schema::Versioned for Impact {
+    fn schema_version_mut(&mut self) -> &mut u32 {
+        &mut self.schema_version
+    }
+}
+
+impl schema::Versioned for WorkspaceTree {
+    fn schema_version_mut(&mut self) -> &mut u32 {
+        &mut self.schema_version
+    }
 }
 
 /// Run the cargo-delta tool with the given command-line arguments.
 pub fn run(host: &mut impl Host, args: impl IntoIterator<Item = String>) {
     let CargoSubcommand::Delta(cli) = Cli::parse_from(args).command;
 
-    let config = match config::load_config(cli.config.clone()) {
+    let config_source = cli.config.clone().map(ConfigSource::File).or_else(|| {
+        let current_dir = host.current_dir().ok()?;
+        config::discover(&current_dir)
+    });
+
+    // Variables that are not valid Unicode cannot name a setting.
+    let env_vars = host
+        .env_vars()
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+
+    let overrides = ConfigOverride::from_env(env_vars).and_then(|mut overrides| {
+        overrides.extend(ConfigOverride::from_args(&cli.set)?);
+        Ok(overrides)
+    });
+
+    let config = match overrides.and_then(|overrides| config::load_config(config_source.as_ref(), &overrides)) {
         Ok(i) => i,
         Err(e) => {
             let _ = writeln!(host.error(), "Error loading config: {e}");
@@ -114,25 +260,140 @@ pub fn run(host: &mut impl Host, args: impl IntoIterator<Item = String>) {
     };
 
     match &cli.command {
-        Commands::Run(run_cmd) => run_command(host, &config, &run_cmd.baseline, &run_cmd.current, cli.config.as_ref()),
+        Commands::Run(run_cmd) => run_command(host, &config, run_cmd, config_source.as_ref()),
+
+        Commands::Analyze(analyze_cmd) => analyze(host, &config, analyze_cmd, config_source.as_ref()),
+
+        Commands::Query(query_cmd) => query_command(host, query_cmd),
 
-        Commands::Analyze(_) => analyze(host, &config, cli.config.as_ref()),
+        Commands::Schema(schema_cmd) => schema_command(host, schema_cmd.document),
+
+        Commands::Config(config_cmd) => match &config_cmd.action {
+            ConfigAction::Check => config_check(host, &config, config_source.as_ref()),
+            ConfigAction::Show(show_cmd) => config_show(host, &config, show_cmd, config_source.as_ref()),
+        },
     }
 }
 
 #[doc(hidden)]
-fn print_common_props(host: &mut impl Host, config_path: Option<&PathBuf>) {
-    if let Some(config_path) = config_path {
+fn print_common_props(host: &mut impl Host, config_source: Option<&ConfigSource>) {
+    if let Some(config_source) = config_source {
         let _ = writeln!(host.error());
-        let _ = writeln!(host.error(), "Using config file  : {}", config_path.display());
+        let _ = writeln!(host.error(), "Using config file  : {config_source}");
     }
 }
 
 #[doc(hidden)]
-fn analyze(host: &mut impl Host, config: &MainConfig, config_path: Option<&PathBuf>) {
+/// Adds the `[package.metadata.delta]` overrides of the workspace crates to `config`.
+fn with_package_configs(host: &mut impl Host, config: &MainConfig, crates: &[&cargo::CargoCrate]) -> Option<MainConfig> {
+    let mut config = config.clone();
+    match config.add_package_configs(crates) {
+        Ok(names) => {
+            if !names.is_empty() {
+                let _ = writeln!(host.error(), "Using [package.metadata.delta] of: {}", names.join(", "));
+                let _ = writeln!(host.error());
+            }
+            Some(config)
+        }
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error loading config: {e}");
+            host.exit(1);
+            None
+        }
+    }
+}
+
+/// Warns about settings naming crates that are not in the workspace. Returns the number of warnings.
+fn warn_unknown_crates(host: &mut impl Host, config: &MainConfig, crates: &[&cargo::CargoCrate]) -> usize {
+    let names: Vec<&str> = crates.iter().map(|crate_| crate_.name.as_str()).collect();
+    let warnings = config.crate_warnings(&names);
+
+    for warning in &warnings {
+        let _ = writeln!(host.error(), "Warning: {warning}");
+    }
+    if !warnings.is_empty() {
+        let _ = writeln!(host.error());
+    }
+
+    warnings.len()
+}
+
+/// The target directory to read build script outputs from, if requested.
+fn build_outputs_dir(host: &mut impl Host, analyze_cmd: &AnalyzeCommand, metadata: &cargo::CargoMetadata) -> Option<PathBuf> {
+    let target_dir = analyze_cmd
+        .target_dir
+        .as_ref()
+        .map(|target_dir| target_dir.clone().unwrap_or_else(|| metadata.target_directory.clone()));
+
+    if let Some(target_dir) = &target_dir {
+        let _ = writeln!(host.error(), "Using build script outputs from {}", target_dir.display());
+        let _ = writeln!(host.error());
+    }
+
+    target_dir
+}
+
+/// Lists the files in the git root that no crate accounts for.
+fn report_unrelated_files(host: &mut impl Host, config: &MainConfig, git_root: &Path, files: &FileNode) {
+    let excludes: Vec<PathBuf> = files.distinct().into_iter().collect();
+
+    let excluded_dirs = files.directories();
+
+    let trip_wire_patterns: Vec<String> = config.trip_wire_patterns.iter().map(|wire| wire.pattern.clone()).collect();
+
+    let unrelated = utils::find_unrelated(
+        git_root,
+        &excludes,
+        &excluded_dirs,
+        &config.file_exclude_patterns,
+        &trip_wire_patterns,
+    );
+
+    if !config.file_exclude_patterns.is_empty() {
+        let _ = writeln!(
+            host.error(),
+            "Excluded patterns       : {}",
+            config.file_exclude_patterns.join(", ")
+        );
+    }
+
+    if !config.trip_wire_patterns.is_empty() {
+        let wires: Vec<String> = config.trip_wire_patterns.iter().map(ToString::to_string).collect();
+        let _ = writeln!(host.error(), "Trip wire patterns      : {}", wires.join(", "));
+    }
+
+    if !unrelated.filtered.is_empty() {
+        let _ = writeln!(host.error());
+        let _ = writeln!(host.error(), "Excluded file(s): (filtered out by exclude patterns)");
+        for file in &unrelated.filtered {
+            let _ = writeln!(host.error(), "  {}", file.display());
+        }
+    }
+
+    if !unrelated.trip_wire.is_empty() {
+        let _ = writeln!(host.error());
+        let _ = writeln!(
+            host.error(),
+            "Trip wire file(s): (changes to these trigger a full or scoped rebuild)"
+        );
+        for file in &unrelated.trip_wire {
+            let _ = writeln!(host.error(), "  {}", file.display());
+        }
+    }
+
+    if !unrelated.unaccounted.is_empty() {
+        let _ = writeln!(host.error());
+        let _ = writeln!(host.error(), "Needs triage: (unknown impact, not matched by any rule)");
+        for file in &unrelated.unaccounted {
+            let _ = writeln!(host.error(), "  {}", file.display());
+        }
+    }
+}
+
+fn analyze(host: &mut impl Host, config: &MainConfig, analyze_cmd: &AnalyzeCommand, config_source: Option<&ConfigSource>) {
     let start = Instant::now();
     let _ = writeln!(host.error(), "Analyzing workspace..");
-    print_common_props(host, config_path);
+    print_common_props(host, config_source);
 
     let metadata = match cargo::metadata(host) {
         Ok(metadata) => metadata,
@@ -159,8 +420,24 @@ fn analyze(host: &mut impl Host, config: &MainConfig, config_path: Option<&PathB
     let _ = writeln!(host.error(), "Detected Cargo workspace : {}", workspace_root.display());
     let _ = writeln!(host.error());
 
+    let target_dir = build_outputs_dir(host, analyze_cmd, &metadata);
+
     let crates = cargo::get_workspace_crates(&metadata);
-    let mut files = files::build_tree(host, &metadata, &crates, config);
+
+    let Some(config) = with_package_configs(host, config, &crates) else {
+        return;
+    };
+    let _ = warn_unknown_crates(host, &config, &crates);
+
+    let mut files = files::build_tree(
+        host,
+        &metadata,
+        &crates,
+        &config,
+        &git_root,
+        target_dir.as_deref(),
+        analyze_cmd.verbose,
+    );
     let crates = crates::parse(&metadata);
 
     files.make_relative_paths(&git_root);
@@ -169,7 +446,21 @@ fn analyze(host: &mut impl Host, config: &MainConfig, config_path: Option<&PathB
     let _ = writeln!(host.error(), "Found {} file(s) in the workspace.", files.len());
     let _ = writeln!(host.error());
 
-    let workspace_tree = WorkspaceTree { files, crates };
+    let manifest_path = workspace_root.join("Cargo.toml");
+    let workspace_tree = WorkspaceTree {
+        schema_version: SCHEMA_VERSION,
+        files,
+        crates,
+        profile: Some(cargo::manifest_value(&manifest_path, &["profile"]).unwrap_or_else(|| serde_json::json!({}))),
+        config_files: config
+            .files
+            .iter()
+            .filter_map(|file| Some(file.normalize().ok()?.as_path().strip_prefix(&git_root).ok()?.to_path_buf()))
+            .collect(),
+        config_metadata: Some(
+            cargo::manifest_value(&manifest_path, &["workspace", "metadata", "delta"]).unwrap_or_else(|| serde_json::json!({})),
+        ),
+    };
 
     match serde_json::to_string_pretty(&workspace_tree) {
         Ok(json_output) => {
@@ -183,54 +474,19 @@ fn analyze(host: &mut impl Host, config: &MainConfig, config_path: Option<&PathB
     }
 
     let _ = writeln!(host.error());
-    let excludes: Vec<PathBuf> = workspace_tree.files.distinct().into_iter().collect();
-
-    let unrelated = utils::find_unrelated(&git_root, &excludes, &config.file_exclude_patterns, &config.trip_wire_patterns);
-
-    if !config.file_exclude_patterns.is_empty() {
-        let _ = writeln!(
-            host.error(),
-            "Excluded patterns       : {}",
-            config.file_exclude_patterns.join(", ")
-        );
-    }
-
-    if !config.trip_wire_patterns.is_empty() {
-        let _ = writeln!(host.error(), "Trip wire patterns      : {}", config.trip_wire_patterns.join(", "));
-    }
-
-    if !unrelated.filtered.is_empty() {
-        let _ = writeln!(host.error());
-        let _ = writeln!(host.error(), "Excluded file(s): (filtered out by exclude patterns)");
-        for file in &unrelated.filtered {
-            let _ = writeln!(host.error(), "  {}", file.display());
-        }
-    }
-
-    if !unrelated.trip_wire.is_empty() {
-        let _ = writeln!(host.error());
-        let _ = writeln!(host.error(), "Trip wire file(s): (changes to these trigger a full rebuild)");
-        for file in &unrelated.trip_wire {
-            let _ = writeln!(host.error(), "  {}", file.display());
-        }
-    }
-
-    if !unrelated.unaccounted.is_empty() {
-        let _ = writeln!(host.error());
-        let _ = writeln!(host.error(), "Needs triage: (unknown impact, not matched by any rule)");
-        for file in &unrelated.unaccounted {
-            let _ = writeln!(host.error(), "  {}", file.display());
-        }
-    }
+    report_unrelated_files(host, &config, &git_root, &workspace_tree.files);
 
     let duration = start.elapsed();
     let _ = writeln!(host.error(), "\nAnalysis finished in {duration:.2?}");
 }
 
 #[doc(hidden)]
-fn run_command(host: &mut impl Host, config: &MainConfig, baseline: &Path, current: &Path, config_path: Option<&PathBuf>) {
+fn run_command(host: &mut impl Host, config: &MainConfig, run_cmd: &RunCommand, config_source: Option<&ConfigSource>) {
+    let baseline = &run_cmd.baseline;
+    let current = &run_cmd.current;
+
     let _ = writeln!(host.error(), "Running delta..\n");
-    print_common_props(host, config_path);
+    print_common_props(host, config_source);
 
     // Get git root to ensure we're working with consistent path bases
     let git_root = match git::get_top_level(host) {
@@ -270,9 +526,12 @@ fn run_command(host: &mut impl Host, config: &MainConfig, baseline: &Path, curre
     let _ = writeln!(host.error());
     let _ = writeln!(host.error(), "Using baseline analysis : {}", baseline.display());
     let _ = writeln!(host.error(), "Using current analysis  : {}", current.display());
+    if let Some(costs_path) = &run_cmd.costs {
+        let _ = writeln!(host.error(), "Using costs file        : {}", costs_path.display());
+    }
     let _ = writeln!(host.error());
 
-    let baseline_tree: WorkspaceTree = match utils::deser_json(baseline) {
+    let baseline_tree: WorkspaceTree = match schema::load(host, baseline) {
         Ok(tree) => tree,
         Err(e) => {
             let _ = writeln!(host.error(), "Error loading current workspace tree: {e}");
@@ -281,7 +540,7 @@ fn run_command(host: &mut impl Host, config: &MainConfig, baseline: &Path, curre
         }
     };
 
-    let current_tree: WorkspaceTree = match utils::deser_json(current) {
+    let current_tree: WorkspaceTree = match schema::load(host, current) {
         Ok(tree) => tree,
         Err(e) => {
             let _ = writeln!(host.error(), "Error loading branch workspace tree: {e}");
@@ -290,7 +549,34 @@ fn run_command(host: &mut impl Host, config: &MainConfig, baseline: &Path, curre
         }
     };
 
-    let result = get_impacted_crates(host, &baseline_tree, &current_tree, &diff, config);
+    let mut result = get_impacted_crates(host, &baseline_tree, &current_tree, &diff, config);
+
+    if run_cmd.layers {
+        result.layers = Some(current_tree.crates.get_layers(&result.required));
+    }
+
+    if let Some(shard_count) = run_cmd.shards {
+        let costs = match &run_cmd.costs {
+            Some(costs_path) => match utils::deser_json(costs_path) {
+                Ok(costs) => costs,
+                Err(e) => {
+                    let _ = writeln!(host.error(), "Error loading costs file: {e}");
+                    host.exit(1);
+                    return;
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        result.shards = Some(shards::split(
+            &result,
+            &current_tree.crates,
+            &current_tree.files,
+            &costs,
+            shard_count.get(),
+            run_cmd.layers,
+        ));
+    }
 
     match serde_json::to_string_pretty(&result) {
         Ok(json_output) => {
@@ -303,122 +589,495 @@ fn run_command(host: &mut impl Host, config: &MainConfig, baseline: &Path, curre
         }
     }
 
-    let total_crates = current_tree.crates.len();
-
-    let required_crates_len = result.required.len();
-    let affected_crates_len = result.affected.len();
-    let modified_crates_len = result.modified.len();
-
-    let _ = writeln!(
-        host.error(),
-        "Modified    {modified_crates_len:>3} (Crates directly modified by Git changes.)"
-    );
-    let _ = writeln!(
-        host.error(),
-        "Affected    {affected_crates_len:>3} (Modified crates plus all their dependents, direct and indirect.)"
-    );
-    let _ = writeln!(
-        host.error(),
-        "Required    {required_crates_len:>3} (Affected crates plus all their dependencies, direct and indirect.)"
-    );
-    let _ = writeln!(host.error(), "Total       {total_crates:>3} (Total crates in this workspace.)");
-    let _ = writeln!(host.error());
+    print_features(host, &result.features);
+    print_summary(host, &result, current_tree.crates.len());
 }
 
 #[doc(hidden)]
-fn get_impacted_crates(
-    host: &mut impl Host,
-    baseline_tree: &WorkspaceTree,
-    current_tree: &WorkspaceTree,
-    git_diff: &GitDiff,
-    config: &MainConfig,
-) -> Impact {
-    let mut modified = HashSet::new();
-
-    if !config.trip_wire_patterns.is_empty() {
-        use glob::Pattern;
-
-        let trip_wire_patterns: Vec<Pattern> = config
-            .trip_wire_patterns
-            .iter()
-            .filter_map(|pattern| Pattern::new(pattern).ok())
-            .collect();
-
-        let mut tripped_files = Vec::new();
-
-        for deleted_file in &git_diff.deleted {
-            let file_str = deleted_file.to_string_lossy();
-            if trip_wire_patterns.iter().any(|pattern| pattern.matches(&file_str)) {
-                tripped_files.push(file_str.to_string());
-            }
+fn query_command(host: &mut impl Host, query_cmd: &QueryCommand) {
+    let tree: WorkspaceTree = match schema::load(host, &query_cmd.analysis) {
+        Ok(tree) => tree,
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error loading workspace analysis: {e}");
+            host.exit(1);
+            return;
         }
+    };
 
-        for changed_file in &git_diff.changed {
-            let file_str = changed_file.to_string_lossy();
-            if trip_wire_patterns.iter().any(|pattern| pattern.matches(&file_str)) {
-                tripped_files.push(file_str.to_string());
+    let impact: Option<Impact> = match &query_cmd.impact {
+        Some(impact_path) => match schema::load_migrating::<Impact>(host, impact_path) {
+            Ok((mut impact, migrated)) => {
+                if migrated {
+                    impact.roots = tree.crates.get_roots(&impact.affected);
+                }
+                Some(impact)
             }
-        }
-
-        if !tripped_files.is_empty() {
-            let _ = writeln!(
-                host.error(),
-                "WARNING: Trip wire activated due to changes in the following file(s):"
-            );
-            for file in &tripped_files {
-                let _ = writeln!(host.error(), "- {file}");
+            Err(e) => {
+                let _ = writeln!(host.error(), "Error loading run result: {e}");
+                host.exit(1);
+                return;
             }
-            let _ = writeln!(host.error());
+        },
+        None => None,
+    };
 
-            let all_crates: HashSet<String> = current_tree.crates.get_all_crate_names().into_iter().collect();
+    let context = query::Context {
+        crates: &tree.crates,
+        files: &tree.files,
+        impact: impact.as_ref(),
+    };
 
-            return Impact {
-                modified: all_crates.clone(),
-                affected: all_crates.clone(),
-                required: all_crates,
-            };
+    let result = match query::evaluate(&query_cmd.expr, &context) {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error evaluating query: {e}");
+            host.exit(1);
+            return;
         }
+    };
 
-        let _ = writeln!(host.error(), "Trip wire is enabled, but no matching files were found, good.");
-        let _ = writeln!(host.error());
+    match serde_json::to_string_pretty(&result) {
+        Ok(json_output) => {
+            let _ = writeln!(host.output(), "{json_output}");
+        }
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error serializing query result to JSON: {e}");
+            host.exit(1);
+            return;
+        }
     }
 
-    for deleted_file in &git_diff.deleted {
-        let crates_for_file = baseline_tree.files.find_crates_containing_file(deleted_file);
+    let _ = writeln!(host.error(), "Query matched {} item(s).", result.len());
+}
 
-        for crate_name in crates_for_file {
-            let _ = modified.insert(crate_name);
+#[doc(hidden)]
+fn config_check(host: &mut impl Host, config: &MainConfig, config_source: Option<&ConfigSource>) {
+    match config_source {
+        Some(config_source) => {
+            let _ = writeln!(host.error(), "Checking config file: {config_source}");
+        }
+        None => {
+            let _ = writeln!(host.error(), "No config file found, checking defaults");
         }
     }
 
-    for changed_file in &git_diff.changed {
-        let crates_for_file = current_tree.files.find_crates_containing_file(changed_file);
-
-        for crate_name in crates_for_file {
-            let _ = modified.insert(crate_name);
+    let metadata = match cargo::metadata(host) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error getting cargo metadata: {e}");
+            host.exit(1);
+            return;
         }
-    }
+    };
 
-    let main_files = baseline_tree.files.distinct();
-    let branch_files = current_tree.files.distinct();
+    let crates = cargo::get_workspace_crates(&metadata);
+    let Some(config) = with_package_configs(host, config, &crates) else {
+        return;
+    };
 
-    for new_file in branch_files.difference(&main_files) {
-        let crates_for_file = current_tree.files.find_crates_containing_file(new_file);
+    let problems = warn_unknown_crates(host, &config, &crates);
+    if problems > 0 {
+        let _ = writeln!(host.error(), "Found {problems} problem(s) in the config");
+        host.exit(1);
+        return;
+    }
 
-        for crate_name in crates_for_file {
-            let _ = modified.insert(crate_name);
+    let _ = writeln!(host.error(), "Config is valid");
+}
+
+fn config_show(host: &mut impl Host, config: &MainConfig, show_cmd: &ConfigShowCommand, config_source: Option<&ConfigSource>) {
+    match config_source {
+        Some(config_source) => {
+            let _ = writeln!(host.error(), "Using config file  : {config_source}");
+        }
+        None => {
+            let _ = writeln!(host.error(), "No config file found, showing defaults");
         }
     }
 
-    // Affected = Modified + all their dependents
-    let mut affected = modified.clone();
-    for crate_name in &modified {
-        if let Some(transitive_dependents) = current_tree.crates.get_dependents_transitive(crate_name) {
-            for dependent in transitive_dependents {
-                let _ = affected.insert(dependent);
+    let mut config = config.clone();
+    if let Some(crate_name) = &show_cmd.crate_name {
+        let metadata = match cargo::metadata(host) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                let _ = writeln!(host.error(), "Error getting cargo metadata: {e}");
+                host.exit(1);
+                return;
             }
-        }
-    }
+        };
+
+        let crates = cargo::get_workspace_crates(&metadata);
+        if !crates.iter().any(|crate_| &crate_.name == crate_name) {
+            let _ = writeln!(host.error(), "Error: '{crate_name}' is not a workspace crate");
+            host.exit(1);
+            return;
+        }
+
+        let Some(with_packages) = with_package_configs(host, &config, &crates) else {
+            return;
+        };
+        config = with_packages;
+    }
+
+    let settings = config.settings(show_cmd.crate_name.as_deref());
+    match show_cmd.format {
+        ConfigFormat::Toml => {
+            let _ = config::write_settings_toml(&mut host.output(), &settings);
+        }
+        ConfigFormat::Json => match serde_json::to_string_pretty(&config::settings_to_json(&settings)) {
+            Ok(json_output) => {
+                let _ = writeln!(host.output(), "{json_output}");
+            }
+            Err(e) => {
+                let _ = writeln!(host.error(), "Error serializing config to JSON: {e}");
+                host.exit(1);
+            }
+        },
+    }
+}
+
+fn schema_command(host: &mut impl Host, document: SchemaDocument) {
+    let schema = match document {
+        SchemaDocument::Analysis => schema::analysis(),
+        SchemaDocument::Impact => schema::impact(),
+    };
+
+    match serde_json::to_string_pretty(&schema) {
+        Ok(json_output) => {
+            let _ = writeln!(host.output(), "{json_output}");
+        }
+        Err(e) => {
+            let _ = writeln!(host.error(), "Error serializing schema to JSON: {e}");
+            host.exit(1);
+        }
+    }
+}
+
+#[doc(hidden)]
+/// Features named in `cfg` predicates guarding the changed files, per crate.
+fn get_touched_features(
+    baseline_tree: &WorkspaceTree,
+    current_tree: &WorkspaceTree,
+    git_diff: &GitDiff,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut touched: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    let deleted = git_diff.deleted.iter().map(|file| baseline_tree.files.find_features_for_file(file));
+    let changed = git_diff.changed.iter().map(|file| current_tree.files.find_features_for_file(file));
+
+    for (crate_name, features) in deleted.chain(changed).flatten() {
+        touched.entry(crate_name).or_default().extend(features);
+    }
+
+    touched
+}
+
+/// Features of the affected crates whose cfg-gated code or optional dependencies were affected.
+fn get_affected_features(
+    baseline_tree: &WorkspaceTree,
+    current_tree: &WorkspaceTree,
+    git_diff: &GitDiff,
+    affected: &BTreeSet<String>,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let touched = get_touched_features(baseline_tree, current_tree, git_diff);
+    let untouched = BTreeSet::new();
+
+    affected
+        .iter()
+        .map(|crate_name| {
+            let touched = touched.get(crate_name).unwrap_or(&untouched);
+            (
+                crate_name.clone(),
+                current_tree.crates.get_affected_features(crate_name, touched, affected),
+            )
+        })
+        .filter(|(_, features)| !features.is_empty())
+        .collect()
+}
+
+fn print_features(host: &mut impl Host, features: &BTreeMap<String, BTreeSet<String>>) {
+    if features.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(host.error(), "Affected features:");
+    for (crate_name, crate_features) in features {
+        let crate_features: Vec<&str> = crate_features.iter().map(String::as_str).collect();
+        let _ = writeln!(host.error(), "- {crate_name}: {}", crate_features.join(", "));
+    }
+    let _ = writeln!(host.error());
+}
+
+fn print_summary(host: &mut impl Host, result: &Impact, total_crates: usize) {
+    let required_crates_len = result.required.len();
+    let affected_crates_len = result.affected.len();
+    let modified_crates_len = result.modified.len();
+    let roots_crates_len = result.roots.len();
+
+    let _ = writeln!(
+        host.error(),
+        "Modified    {modified_crates_len:>3} (Crates directly modified by Git changes.)"
+    );
+    let _ = writeln!(
+        host.error(),
+        "Affected    {affected_crates_len:>3} (Modified crates plus all their dependents, direct and indirect.)"
+    );
+    let _ = writeln!(
+        host.error(),
+        "Required    {required_crates_len:>3} (Affected crates plus all their dependencies, direct and indirect.)"
+    );
+    let _ = writeln!(
+        host.error(),
+        "Roots       {roots_crates_len:>3} (Affected crates that no other affected crate depends on.)"
+    );
+    if !result.groups.is_empty() {
+        let groups_len = result.groups.len();
+        let _ = writeln!(
+            host.error(),
+            "Groups      {groups_len:>3} (Configured crate groups with an affected member: {}.)",
+            result.groups.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+    if let Some(layers) = &result.layers {
+        let layers_len = layers.len();
+        let _ = writeln!(
+            host.error(),
+            "Layers      {layers_len:>3} (Required crates grouped by dependency depth.)"
+        );
+    }
+    if let Some(shards) = &result.shards {
+        let shards_len = shards.len();
+        let _ = writeln!(
+            host.error(),
+            "Shards      {shards_len:>3} (Affected crates split into balanced buckets.)"
+        );
+    }
+    let _ = writeln!(host.error(), "Total       {total_crates:>3} (Total crates in this workspace.)");
+    let _ = writeln!(host.error());
+}
+
+#[doc(hidden)]
+/// Crates marked as modified by trip wires.
+enum TrippedCrates {
+    All,
+    Scoped(BTreeSet<String>),
+}
+
+/// Whether a recorded manifest section differs between the analyses. A section missing from
+/// either analysis is unknown, not changed.
+fn section_changed(baseline: Option<&serde_json::Value>, current: Option<&serde_json::Value>) -> bool {
+    matches!((baseline, current), (Some(baseline), Some(current)) if baseline != current)
+}
+
+/// Why a change to `file` trips a built-in trip wire. The workspace `Cargo.toml` trips it only when
+/// its `[profile]` or `[workspace.metadata.delta]` differs between the analyses.
+fn default_trip_wire_reason(
+    baseline_tree: &WorkspaceTree,
+    current_tree: &WorkspaceTree,
+    config: &MainConfig,
+    file: &Path,
+) -> Option<&'static str> {
+    let defaults = config.default_trip_wires;
+    let is_manifest = file == current_tree.files.path;
+
+    let workspace_dir = current_tree.files.path.parent().unwrap_or_else(|| Path::new(""));
+
+    if let Some(reason) = defaults.reason(file, workspace_dir) {
+        return Some(reason);
+    }
+
+    if defaults.profile && is_manifest && section_changed(baseline_tree.profile.as_ref(), current_tree.profile.as_ref()) {
+        return Some("[profile] of the workspace Cargo.toml");
+    }
+
+    let is_config_file = baseline_tree
+        .config_files
+        .iter()
+        .chain(&current_tree.config_files)
+        .any(|config_file| config_file == file);
+    let is_config_metadata = is_manifest && section_changed(baseline_tree.config_metadata.as_ref(), current_tree.config_metadata.as_ref());
+    (defaults.config && (is_config_file || is_config_metadata)).then_some("delta config")
+}
+
+/// Matches changed and deleted files against the built-in and configured trip wires. Scoped trip
+/// wires mark the crates matching their `crates` globs and their `dependents_of` crate, whose
+/// dependents follow as affected. Files mapped to crates by `[[map]]` rules mark those crates instead.
+fn check_trip_wires(
+    host: &mut impl Host,
+    baseline_tree: &WorkspaceTree,
+    current_tree: &WorkspaceTree,
+    git_diff: &GitDiff,
+    config: &MainConfig,
+) -> TrippedCrates {
+    let all_crates = current_tree.crates.get_all_crate_names();
+    let mut tripped_files = Vec::new();
+    let mut scoped_files = Vec::new();
+    let mut scoped_crates = BTreeSet::new();
+
+    for file in git_diff.deleted.iter().chain(&git_diff.changed) {
+        let file_str = file.to_string_lossy().to_string();
+
+        // Built-in trip wires hold even for mapped files, map rules only bypass the configured ones.
+        if let Some(reason) = default_trip_wire_reason(baseline_tree, current_tree, config, file) {
+            tripped_files.push(format!("{file_str} ({reason})"));
+            continue;
+        }
+
+        if config.map.iter().any(|rule| rule.matches(file)) {
+            continue;
+        }
+
+        let wires: Vec<&TripWire> = config.trip_wire_patterns.iter().filter(|wire| wire.matches(file)).collect();
+
+        if wires.iter().any(|wire| !wire.is_scoped()) {
+            tripped_files.push(file_str);
+        } else if !wires.is_empty() {
+            let mut crates = BTreeSet::new();
+            for wire in wires {
+                crates.extend(config::select_crates(&wire.crates, &all_crates));
+                crates.extend(wire.dependents_of.iter().filter(|name| all_crates.contains(name)).cloned());
+            }
+            scoped_files.push((file_str, crates.iter().cloned().collect::<Vec<_>>().join(", ")));
+            scoped_crates.extend(crates);
+        }
+    }
+
+    if !tripped_files.is_empty() {
+        let _ = writeln!(
+            host.error(),
+            "WARNING: Trip wire activated due to changes in the following file(s):"
+        );
+        for file in &tripped_files {
+            let _ = writeln!(host.error(), "- {file}");
+        }
+        let _ = writeln!(host.error());
+        return TrippedCrates::All;
+    }
+
+    if scoped_files.is_empty() {
+        if !config.trip_wire_patterns.is_empty() {
+            let _ = writeln!(host.error(), "Trip wire is enabled, but no matching files were found, good.");
+            let _ = writeln!(host.error());
+        }
+    } else {
+        let _ = writeln!(
+            host.error(),
+            "WARNING: Scoped trip wire activated due to changes in the following file(s):"
+        );
+        for (file, crates) in &scoped_files {
+            let _ = writeln!(host.error(), "- {file}: {crates}");
+        }
+        let _ = writeln!(host.error());
+    }
+
+    TrippedCrates::Scoped(scoped_crates)
+}
+
+/// Adds `always_crates` to `affected`, then every member of a group with an affected member.
+fn expand_groups(config: &MainConfig, crate_names: &[String], affected: &mut BTreeSet<String>) {
+    affected.extend(config::select_crates(&config.always_crates, crate_names));
+
+    let groups: Vec<BTreeSet<String>> = config
+        .groups
+        .values()
+        .map(|members| config::select_crates(members, crate_names))
+        .collect();
+
+    // A crate in several groups pulls in all of them, so repeat until nothing is added.
+    let mut expanded = true;
+    while expanded {
+        expanded = false;
+        for members in &groups {
+            if !members.is_disjoint(affected) && !members.is_subset(affected) {
+                affected.extend(members.iter().cloned());
+                expanded = true;
+            }
+        }
+    }
+}
+
+/// Names of the groups with an affected member.
+fn impacted_groups(config: &MainConfig, crate_names: &[String], affected: &BTreeSet<String>) -> BTreeSet<String> {
+    config
+        .groups
+        .iter()
+        .filter(|(_, members)| !config::select_crates(members, crate_names).is_disjoint(affected))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+fn get_impacted_crates(
+    host: &mut impl Host,
+    baseline_tree: &WorkspaceTree,
+    current_tree: &WorkspaceTree,
+    git_diff: &GitDiff,
+    config: &MainConfig,
+) -> Impact {
+    let mut modified = BTreeSet::new();
+    let crate_names = current_tree.crates.get_all_crate_names();
+    let ignored = config::select_crates(&config.ignore_crates, &crate_names);
+
+    match check_trip_wires(host, baseline_tree, current_tree, git_diff, config) {
+        TrippedCrates::All => {
+            let all_crates: BTreeSet<String> = crate_names.iter().filter(|name| !ignored.contains(*name)).cloned().collect();
+
+            return Impact {
+                schema_version: SCHEMA_VERSION,
+                modified: all_crates.clone(),
+                affected: all_crates.clone(),
+                roots: current_tree.crates.get_roots(&all_crates),
+                groups: impacted_groups(config, &crate_names, &all_crates),
+                required: all_crates,
+                features: BTreeMap::new(),
+                layers: None,
+                shards: None,
+            };
+        }
+        TrippedCrates::Scoped(crates) => modified.extend(crates),
+    }
+
+    for deleted_file in &git_diff.deleted {
+        let crates_for_file = baseline_tree.files.find_crates_containing_file(deleted_file);
+
+        for crate_name in crates_for_file {
+            let _ = modified.insert(crate_name);
+        }
+    }
+
+    for changed_file in &git_diff.changed {
+        let crates_for_file = current_tree.files.find_crates_containing_file(changed_file);
+
+        for crate_name in crates_for_file {
+            let _ = modified.insert(crate_name);
+        }
+    }
+
+    let main_files = baseline_tree.files.distinct();
+    let branch_files = current_tree.files.distinct();
+
+    for new_file in branch_files.difference(&main_files) {
+        let crates_for_file = current_tree.files.find_crates_containing_file(new_file);
+
+        for crate_name in crates_for_file {
+            let _ = modified.insert(crate_name);
+        }
+    }
+
+    // Affected = Modified + all their dependents
+    let mut affected = modified.clone();
+    for crate_name in &modified {
+        if let Some(transitive_dependents) = current_tree.crates.get_dependents_transitive(crate_name) {
+            for dependent in transitive_dependents {
+                let _ = affected.insert(dependent);
+            }
+        }
+    }
+
+    expand_groups(config, &crate_names, &mut affected);
+
+    // Ignored crates never appear in the result, not even as dependencies
+    modified.retain(|crate_name| !ignored.contains(crate_name));
+    affected.retain(|crate_name| !ignored.contains(crate_name));
 
     // Required = Affected + all their dependencies
     let mut required = affected.clone();
@@ -430,10 +1089,23 @@ fn get_impacted_crates(
         }
     }
 
+    required.retain(|crate_name| !ignored.contains(crate_name));
+
+    // Roots = Affected minus crates that other affected crates depend on
+    let roots = current_tree.crates.get_roots(&affected);
+
+    let features = get_affected_features(baseline_tree, current_tree, git_diff, &affected);
+
     Impact {
+        schema_version: SCHEMA_VERSION,
+        groups: impacted_groups(config, &crate_names, &affected),
         modified,
         affected,
         required,
+        roots,
+        features,
+        layers: None,
+        shards: None,
     }
 }
 
@@ -464,8 +1136,12 @@ mod tests {
                     .map(|d| CargoDependency {
                         name: d.to_string(),
                         source: None,
+                        optional: false,
+                        rename: None,
                     })
                     .collect(),
+                features: BTreeMap::new(),
+                metadata: None,
             });
         }
         CargoMetadata {
@@ -497,8 +1173,12 @@ mod tests {
         let crates_graph = crates::parse(&metadata);
 
         WorkspaceTree {
+            schema_version: SCHEMA_VERSION,
             files,
             crates: crates_graph,
+            profile: Some(serde_json::json!({})),
+            config_files: vec![],
+            config_metadata: Some(serde_json::json!({})),
         }
     }
 
@@ -579,6 +1259,28 @@ mod tests {
         assert!(result.required.contains("app"));
     }
 
+    #[test]
+    fn roots_drop_affected_dependencies() {
+        let mut host = TestHost::new();
+        // app -> middleware -> core, cli -> core
+        let tree = make_workspace(&[
+            ("app", &["app/src/main.rs"], &["middleware"]),
+            ("cli", &["cli/src/main.rs"], &["core"]),
+            ("middleware", &["middleware/src/lib.rs"], &["core"]),
+            ("core", &["core/src/lib.rs"], &[]),
+        ]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("core/src/lib.rs")],
+            deleted: vec![],
+        };
+        let config = MainConfig::default();
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert_eq!(result.affected.len(), 4);
+        assert_eq!(result.roots, ["app", "cli"].iter().map(ToString::to_string).collect());
+    }
+
     #[test]
     fn deleted_file_marks_crate_modified() {
         let mut host = TestHost::new();
@@ -611,6 +1313,73 @@ mod tests {
         assert!(result.modified.contains("lib"));
     }
 
+    #[test]
+    fn new_file_in_claimed_directory_marks_crate_modified() {
+        let mut host = TestHost::new();
+        let mut tree = make_workspace(&[("app", &["app/src/main.rs"], &["db"]), ("db", &["db/src/lib.rs"], &[])]);
+        tree.files.children[1].add_child(FileNode::new(PathBuf::from("db/migrations/**"), FileKind::Directory));
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("db/migrations/002_users.sql")],
+            deleted: vec![],
+        };
+        let config = MainConfig::default();
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert_eq!(result.modified, BTreeSet::from(["db".to_string()]));
+        assert!(result.affected.contains("app"));
+    }
+
+    #[test]
+    fn features_follow_cfg_gated_modules() {
+        let mut tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("db", &["db/src/lib.rs"], &[])]);
+        let mut pg = FileNode::new(PathBuf::from("db/src/pg.rs"), FileKind::Module);
+        pg.cfg = Some(r#"all(unix, feature = "postgres")"#.to_string());
+        tree.files.children[1].children[0].add_child(pg);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("db/src/pg.rs"), PathBuf::from("app/src/main.rs")],
+            deleted: vec![],
+        };
+        let mut host = TestHost::new();
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &MainConfig::default());
+
+        assert_eq!(result.features.len(), 1);
+        assert_eq!(result.features["db"], BTreeSet::from(["postgres".to_string()]));
+
+        print_features(&mut host, &result.features);
+        assert!(host.stderr_str().contains("- db: postgres"));
+    }
+
+    #[test]
+    fn features_include_those_enabling_affected_optional_deps() {
+        let mut metadata = make_metadata(&[("db", &["pg-driver"]), ("pg-driver", &[])]);
+        metadata.packages[0].dependencies[0].optional = true;
+        let _ = metadata.packages[0]
+            .features
+            .insert("postgres".to_string(), vec!["dep:pg-driver".to_string()]);
+        let _ = metadata.packages[0]
+            .features
+            .insert("full".to_string(), vec!["postgres".to_string()]);
+        let tree = WorkspaceTree {
+            schema_version: SCHEMA_VERSION,
+            files: make_file_tree(&[("db", &["db/src/lib.rs"]), ("pg-driver", &["pg-driver/src/lib.rs"])]),
+            crates: crates::parse(&metadata),
+            profile: None,
+            config_files: vec![],
+            config_metadata: None,
+        };
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("pg-driver/src/lib.rs")],
+            deleted: vec![],
+        };
+
+        let result = get_impacted_crates(&mut TestHost::new(), &tree, &tree, &diff, &MainConfig::default());
+
+        assert_eq!(result.features["db"], BTreeSet::from(["full".to_string(), "postgres".to_string()]));
+        assert!(!result.features.contains_key("pg-driver"));
+    }
+
     #[test]
     fn trip_wire_activated_returns_all_crates() {
         let mut host = TestHost::new();
@@ -620,7 +1389,7 @@ mod tests {
             deleted: vec![],
         };
         let config = MainConfig {
-            trip_wire_patterns: vec!["Cargo.lock".to_string()],
+            trip_wire_patterns: vec![TripWire::from("Cargo.lock")],
             ..MainConfig::default()
         };
 
@@ -634,54 +1403,460 @@ mod tests {
     }
 
     #[test]
-    fn trip_wire_enabled_no_match() {
+    fn mapped_files_mark_their_crates_instead_of_tripping() {
         let mut host = TestHost::new();
-        let tree = make_workspace(&[("lib", &["lib/src/lib.rs"], &[])]);
+        let mut tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("lib", &["lib/src/lib.rs"], &[])]);
+        tree.files.children[1].add_child(FileNode::new(PathBuf::from("schemas/user.json"), FileKind::Mapped));
         let diff = GitDiff {
-            changed: vec![PathBuf::from("lib/src/lib.rs")],
+            changed: vec![PathBuf::from("schemas/user.json")],
             deleted: vec![],
         };
         let config = MainConfig {
-            trip_wire_patterns: vec!["Cargo.lock".to_string()],
+            trip_wire_patterns: vec![TripWire::from("schemas/*")],
+            map: vec![config::MapRule {
+                pattern: "schemas/*.json".to_string(),
+                crates: vec!["lib".to_string()],
+            }],
             ..MainConfig::default()
         };
 
         let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
 
-        assert!(result.modified.contains("lib"));
-        assert!(host.stderr_str().contains("no matching files were found"));
+        assert_eq!(result.modified, BTreeSet::from(["lib".to_string()]));
+        assert!(!host.stderr_str().contains("Trip wire activated"));
     }
 
     #[test]
-    fn trip_wire_on_deleted_file() {
+    fn scoped_trip_wires_mark_their_crates() {
         let mut host = TestHost::new();
-        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[])]);
+        let tree = make_workspace(&[
+            ("api-server", &["api-server/src/main.rs"], &["db-core"]),
+            ("api-client", &["api-client/src/lib.rs"], &[]),
+            ("db-core", &["db-core/src/lib.rs"], &[]),
+            ("web", &["web/src/main.rs"], &[]),
+        ]);
         let diff = GitDiff {
-            changed: vec![],
-            deleted: vec![PathBuf::from("Cargo.lock")],
+            changed: vec![PathBuf::from("proto/api.proto"), PathBuf::from("ci/db/init.sql")],
+            deleted: vec![],
         };
         let config = MainConfig {
-            trip_wire_patterns: vec!["Cargo.lock".to_string()],
+            trip_wire_patterns: vec![
+                TripWire {
+                    crates: vec!["api-*".to_string()],
+                    ..TripWire::from("proto/**")
+                },
+                TripWire {
+                    dependents_of: Some("db-core".to_string()),
+                    ..TripWire::from("ci/db/**")
+                },
+            ],
             ..MainConfig::default()
         };
 
         let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
 
-        assert!(result.modified.contains("app"));
+        let names = |names: &[&str]| names.iter().map(ToString::to_string).collect::<BTreeSet<_>>();
+        assert_eq!(result.modified, names(&["api-client", "api-server", "db-core"]));
+        assert_eq!(result.affected, names(&["api-client", "api-server", "db-core"]));
+        assert!(!host.stderr_str().contains("WARNING: Trip wire activated"));
+        assert!(host.stderr_str().contains("- proto/api.proto: api-client, api-server"));
+        assert!(host.stderr_str().contains("- ci/db/init.sql: db-core"));
+    }
+
+    #[test]
+    fn plain_trip_wire_wins_over_scoped_one() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("proto/api.proto")],
+            deleted: vec![],
+        };
+        let config = MainConfig {
+            trip_wire_patterns: vec![
+                TripWire {
+                    crates: vec!["lib".to_string()],
+                    ..TripWire::from("proto/**")
+                },
+                TripWire::from("*.proto"),
+            ],
+            ..MainConfig::default()
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert_eq!(result.modified.len(), 2);
+        assert!(host.stderr_str().contains("WARNING: Trip wire activated"));
+    }
+
+    #[test]
+    fn default_trip_wires_name_their_reason() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("rust-toolchain.toml")],
+            deleted: vec![],
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &MainConfig::default());
+
+        assert_eq!(result.modified.len(), 2);
+        assert!(host.stderr_str().contains("- rust-toolchain.toml (Rust toolchain)"));
+
+        let mut host = TestHost::new();
+        let mut config = MainConfig::default();
+        config.default_trip_wires.toolchain = false;
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert!(result.modified.is_empty());
+        assert!(!host.stderr_str().contains("Trip wire activated"));
+    }
+
+    #[test]
+    fn map_rules_do_not_bypass_default_trip_wires() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("rust-toolchain.toml")],
+            deleted: vec![],
+        };
+        let config = MainConfig {
+            map: vec![config::MapRule {
+                pattern: "**/*.toml".to_string(),
+                crates: vec!["lib".to_string()],
+            }],
+            ..MainConfig::default()
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert_eq!(result.modified.len(), 2);
+        assert!(host.stderr_str().contains("- rust-toolchain.toml (Rust toolchain)"));
+    }
+
+    #[test]
+    fn workspace_manifest_trips_on_profile_or_config_changes_only() {
+        let baseline = make_workspace(&[("app", &["app/src/main.rs"], &[])]);
+        let mut current = baseline.clone();
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("Cargo.toml")],
+            deleted: vec![],
+        };
+
+        let mut host = TestHost::new();
+        let result = get_impacted_crates(&mut host, &baseline, &current, &diff, &MainConfig::default());
+        assert!(result.modified.is_empty());
+
+        current.profile = Some(serde_json::json!({ "release": { "lto": true } }));
+        let mut host = TestHost::new();
+        let result = get_impacted_crates(&mut host, &baseline, &current, &diff, &MainConfig::default());
+        assert!(result.modified.contains("app"));
+        assert!(host.stderr_str().contains("- Cargo.toml ([profile] of the workspace Cargo.toml)"));
+
+        current.profile = Some(serde_json::json!({}));
+        current.config_metadata = Some(serde_json::json!({ "trip_wire_patterns": ["Cargo.lock"] }));
+        let mut host = TestHost::new();
+        let result = get_impacted_crates(&mut host, &baseline, &current, &diff, &MainConfig::default());
+        assert!(result.modified.contains("app"));
+        assert!(host.stderr_str().contains("- Cargo.toml (delta config)"));
+
+        current.config_files = vec![PathBuf::from("delta.toml")];
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("delta.toml")],
+            deleted: vec![],
+        };
+        let mut host = TestHost::new();
+        let result = get_impacted_crates(&mut host, &baseline, &current, &diff, &MainConfig::default());
+        assert!(result.modified.contains("app"));
+        assert!(host.stderr_str().contains("- delta.toml (delta config)"));
+    }
+
+    #[test]
+    fn legacy_baseline_without_manifest_sections_does_not_trip() {
+        let mut baseline = make_workspace(&[("app", &["app/src/main.rs"], &[])]);
+        baseline.profile = None;
+        baseline.config_metadata = None;
+        let mut current = baseline.clone();
+        current.profile = Some(serde_json::json!({ "release": { "lto": true } }));
+        current.config_metadata = Some(serde_json::json!({}));
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("Cargo.toml")],
+            deleted: vec![],
+        };
+
+        let mut host = TestHost::new();
+        let result = get_impacted_crates(&mut host, &baseline, &current, &diff, &MainConfig::default());
+
+        assert!(result.modified.is_empty());
+        assert!(!host.stderr_str().contains("Trip wire activated"));
+    }
+
+    #[test]
+    fn crate_rules_filter_and_expand_impact() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[
+            ("xtask", &["xtask/src/main.rs"], &["lib"]),
+            ("lib", &["lib/src/lib.rs"], &[]),
+            ("web", &["web/src/main.rs"], &[]),
+            ("web-assets", &["web-assets/src/lib.rs"], &[]),
+            ("smoke", &["smoke/src/main.rs"], &[]),
+            ("other", &["other/src/main.rs"], &[]),
+        ]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("lib/src/lib.rs"), PathBuf::from("web/src/main.rs")],
+            deleted: vec![],
+        };
+        let config: MainConfig = toml::from_str(
+            r#"
+            ignore_crates = ["xtask"]
+            always_crates = ["smoke"]
+
+            [groups]
+            frontend = ["web*"]
+            misc = ["other"]
+            "#,
+        )
+        .unwrap();
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        let names = |names: &[&str]| names.iter().map(ToString::to_string).collect::<BTreeSet<_>>();
+        assert_eq!(result.modified, names(&["lib", "web"]));
+        assert_eq!(result.affected, names(&["lib", "smoke", "web", "web-assets"]));
+        assert_eq!(result.required, names(&["lib", "smoke", "web", "web-assets"]));
+        assert_eq!(result.groups, names(&["frontend"]));
+    }
+
+    #[test]
+    fn trip_wire_leaves_out_ignored_crates() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[]), ("xtask", &["xtask/src/main.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("Cargo.lock")],
+            deleted: vec![],
+        };
+        let config = MainConfig {
+            trip_wire_patterns: vec![TripWire::from("Cargo.lock")],
+            ignore_crates: vec!["xtask".to_string()],
+            groups: BTreeMap::from([("tools".to_string(), vec!["xtask".to_string()])]),
+            ..MainConfig::default()
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert_eq!(result.affected, BTreeSet::from(["app".to_string()]));
+        assert!(result.groups.is_empty());
+    }
+
+    #[test]
+    fn trip_wire_enabled_no_match() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("lib", &["lib/src/lib.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![PathBuf::from("lib/src/lib.rs")],
+            deleted: vec![],
+        };
+        let config = MainConfig {
+            trip_wire_patterns: vec![TripWire::from("Cargo.lock")],
+            ..MainConfig::default()
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert!(result.modified.contains("lib"));
+        assert!(host.stderr_str().contains("no matching files were found"));
+    }
+
+    #[test]
+    fn trip_wire_on_deleted_file() {
+        let mut host = TestHost::new();
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &[])]);
+        let diff = GitDiff {
+            changed: vec![],
+            deleted: vec![PathBuf::from("Cargo.lock")],
+        };
+        let config = MainConfig {
+            trip_wire_patterns: vec![TripWire::from("Cargo.lock")],
+            ..MainConfig::default()
+        };
+
+        let result = get_impacted_crates(&mut host, &tree, &tree, &diff, &config);
+
+        assert!(result.modified.contains("app"));
         assert!(host.stderr_str().contains("Trip wire activated"));
     }
 
+    // --- query tests ---
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn query_subcommand_prints_sorted_result() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_query");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &["lib"]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let analysis_path = tmp.join("analysis.json");
+        std::fs::write(&analysis_path, serde_json::to_string_pretty(&tree).unwrap()).unwrap();
+
+        let mut host = TestHost::new();
+        run(
+            &mut host,
+            [
+                "cargo",
+                "delta",
+                "query",
+                "deps(app)",
+                "--analysis",
+                &analysis_path.to_string_lossy(),
+            ]
+            .iter()
+            .map(ToString::to_string),
+        );
+
+        assert!(host.exit_code.is_none());
+        let result: Vec<String> = serde_json::from_str(&host.stdout_str()).unwrap();
+        assert_eq!(result, vec!["app", "lib"]);
+        assert!(host.stderr_str().contains("Query matched 2 item(s)"));
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn query_subcommand_migrates_legacy_run_result() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_query_legacy_impact");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &["lib"]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let analysis_path = tmp.join("analysis.json");
+        std::fs::write(&analysis_path, serde_json::to_string_pretty(&tree).unwrap()).unwrap();
+        // Run result as written by cargo-delta 0.2.
+        let impact_path = tmp.join("impact.json");
+        std::fs::write(
+            &impact_path,
+            r#"{"Modified": ["lib"], "Affected": ["lib", "app"], "Required": ["app", "lib"]}"#,
+        )
+        .unwrap();
+
+        let mut host = TestHost::new();
+        run(
+            &mut host,
+            [
+                "cargo",
+                "delta",
+                "query",
+                "impacted(roots)",
+                "--analysis",
+                &analysis_path.to_string_lossy(),
+                "--impact",
+                &impact_path.to_string_lossy(),
+            ]
+            .iter()
+            .map(ToString::to_string),
+        );
+        let _ = std::fs::remove_dir_all(&tmp);
+
+        assert!(host.exit_code.is_none(), "{}", host.stderr_str());
+        let result: Vec<String> = serde_json::from_str(&host.stdout_str()).unwrap();
+        assert_eq!(result, vec!["app"]);
+        assert!(host.stderr_str().contains("migrating"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn query_subcommand_invalid_expression_exits_with_error() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_query_invalid");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let tree = make_workspace(&[("lib", &["lib/src/lib.rs"], &[])]);
+        let analysis_path = tmp.join("analysis.json");
+        std::fs::write(&analysis_path, serde_json::to_string_pretty(&tree).unwrap()).unwrap();
+
+        let mut host = TestHost::new();
+        run(
+            &mut host,
+            [
+                "cargo",
+                "delta",
+                "query",
+                "deps(lib",
+                "--analysis",
+                &analysis_path.to_string_lossy(),
+            ]
+            .iter()
+            .map(ToString::to_string),
+        );
+
+        assert_eq!(host.exit_code, Some(1));
+        assert!(host.stderr_str().contains("Error evaluating query"));
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    // --- schema tests ---
+
+    #[test]
+    fn schema_subcommand_prints_json_schema() {
+        let mut host = TestHost::new();
+        run(&mut host, ["cargo", "delta", "schema", "impact"].iter().map(ToString::to_string));
+
+        assert!(host.exit_code.is_none());
+        let schema: serde_json::Value = serde_json::from_str(&host.stdout_str()).unwrap();
+        assert_eq!(schema["title"], "cargo-delta run result");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn run_subcommand_rejects_newer_schema_version() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_run_schema");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let mut tree = make_workspace(&[("lib", &["lib/src/lib.rs"], &[])]);
+        tree.schema_version = SCHEMA_VERSION + 1;
+        let analysis_path = tmp.join("analysis.json");
+        std::fs::write(&analysis_path, serde_json::to_string_pretty(&tree).unwrap()).unwrap();
+
+        let git_root = tmp.to_string_lossy().to_string();
+        let mut host = TestHost::new().with_commands(vec![
+            Ok(success_output(&format!("{git_root}\n"))),   // git rev-parse
+            Ok(success_output("abc\trefs/heads/master\n")), // git ls-remote
+            Ok(success_output("abc123\n")),                 // git merge-base
+            Ok(success_output("lib/src/lib.rs\n")),         // git diff (one file)
+        ]);
+
+        let analysis = analysis_path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "run", "--baseline", &analysis, "--current", &analysis]
+                .iter()
+                .map(ToString::to_string),
+        );
+
+        assert_eq!(host.exit_code, Some(1));
+        assert!(host.stderr_str().contains("schema version"));
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
     // --- print_common_props tests ---
 
     #[test]
     fn print_common_props_with_path() {
         let mut host = TestHost::new();
-        let path = PathBuf::from("my-config.toml");
-        print_common_props(&mut host, Some(&path));
+        let source = ConfigSource::File(PathBuf::from("my-config.toml"));
+        print_common_props(&mut host, Some(&source));
         assert!(host.stderr_str().contains("Using config file"));
         assert!(host.stderr_str().contains("my-config.toml"));
     }
 
+    #[test]
+    fn print_common_props_with_workspace_metadata() {
+        let mut host = TestHost::new();
+        let source = ConfigSource::WorkspaceMetadata(PathBuf::from("Cargo.toml"));
+        print_common_props(&mut host, Some(&source));
+        assert!(host.stderr_str().contains("Cargo.toml [workspace.metadata.delta]"));
+    }
+
     #[test]
     fn print_common_props_without_path() {
         let mut host = TestHost::new();
@@ -704,6 +1879,150 @@ mod tests {
         assert!(host.stderr_str().contains("Error loading config"));
     }
 
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn config_check_reports_invalid_config_with_line() {
+        let path = std::env::temp_dir().join("cargo_delta_test_config_check_invalid.toml");
+        std::fs::write(&path, "[parser]\nmods = true\nfle_refs = false\n").unwrap();
+
+        let mut host = TestHost::new();
+        let config = path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "-c", &config, "config", "check"].iter().map(ToString::to_string),
+        );
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(host.exit_code, Some(1));
+        assert!(host.stderr_str().contains("cargo_delta_test_config_check_invalid.toml"));
+        assert!(host.stderr_str().contains("line 3"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn config_check_warns_about_overrides_without_crate() {
+        let path = std::env::temp_dir().join("cargo_delta_test_config_check_overrides.toml");
+        std::fs::write(&path, "[parser.\"lib*\"]\nmods = true\n\n[parser.gone]\nmods = true\n").unwrap();
+
+        let metadata = serde_json::to_string(&make_metadata(&[("lib", &[])])).unwrap();
+        let mut host = TestHost::new().with_commands(vec![Ok(success_output(&metadata))]);
+        let config = path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "-c", &config, "config", "check"].iter().map(ToString::to_string),
+        );
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(host.exit_code, Some(1));
+        assert!(host.stderr_str().contains("[parser.gone] matches no workspace crate"));
+        assert!(!host.stderr_str().contains("[parser.lib*]"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn config_show_prints_crate_settings_with_origins() {
+        let path = std::env::temp_dir().join("cargo_delta_test_config_show.toml");
+        std::fs::write(&path, "[parser]\nassume = true\n\n[parser.lib]\nmods = false\n").unwrap();
+
+        let metadata = serde_json::to_string(&make_metadata(&[("lib", &[])])).unwrap();
+        let mut host = TestHost::new().with_commands(vec![Ok(success_output(&metadata))]);
+        let config = path.to_string_lossy();
+        run(
+            &mut host,
+            [
+                "cargo", "delta", "-c", &config, "config", "show", "--crate", "lib", "--format", "json",
+            ]
+            .iter()
+            .map(ToString::to_string),
+        );
+        let _ = std::fs::remove_file(&path);
+
+        let shown: serde_json::Value = serde_json::from_slice(&host.stdout).unwrap();
+        assert_eq!(shown["parser"]["mods"]["value"], false);
+        assert!(shown["parser"]["mods"]["origin"].as_str().unwrap().starts_with("[parser.lib] in "));
+        assert_eq!(shown["parser"]["assume"]["origin"], config.as_ref());
+        assert_eq!(shown["parser"]["file_refs"]["origin"], "default");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn config_show_applies_host_env_overrides() {
+        let path = std::env::temp_dir().join("cargo_delta_test_config_show_env.toml");
+        std::fs::write(&path, "[git]\nremote_branch = \"origin/main\"\n").unwrap();
+
+        let metadata = serde_json::to_string(&make_metadata(&[("lib", &[])])).unwrap();
+        let mut host = TestHost::new()
+            .with_commands(vec![Ok(success_output(&metadata))])
+            .with_env_vars(&[("DELTA_GIT__REMOTE_BRANCH", "origin/dev"), ("HOME", "/home/ci")]);
+        let config = path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "-c", &config, "config", "show", "--format", "json"]
+                .iter()
+                .map(ToString::to_string),
+        );
+        let _ = std::fs::remove_file(&path);
+
+        let shown: serde_json::Value = serde_json::from_slice(&host.stdout).unwrap();
+        assert_eq!(shown["git"]["remote_branch"]["value"], "origin/dev");
+        assert_eq!(shown["git"]["remote_branch"]["origin"], "DELTA_GIT__REMOTE_BRANCH");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[cfg_attr(miri, ignore)]
+    fn non_unicode_env_vars_are_skipped() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let path = std::env::temp_dir().join("cargo_delta_test_config_show_non_unicode_env.toml");
+        std::fs::write(&path, "[git]\nremote_branch = \"origin/main\"\n").unwrap();
+
+        let metadata = serde_json::to_string(&make_metadata(&[("lib", &[])])).unwrap();
+        let mut host = TestHost::new()
+            .with_commands(vec![Ok(success_output(&metadata))])
+            .with_env_vars_os(vec![
+                ("BAD".into(), std::ffi::OsString::from_vec(vec![0xff])),
+                ("DELTA_GIT__REMOTE_BRANCH".into(), "origin/dev".into()),
+            ]);
+        let config = path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "-c", &config, "config", "show", "--format", "json"]
+                .iter()
+                .map(ToString::to_string),
+        );
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(host.exit_code, None);
+        let shown: serde_json::Value = serde_json::from_slice(&host.stdout).unwrap();
+        assert_eq!(shown["git"]["remote_branch"]["value"], "origin/dev");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn config_is_discovered_from_host_current_dir() {
+        let root = std::env::temp_dir().join("cargo_delta_test_discover_host_dir");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("crates/lib")).unwrap();
+        std::fs::write(root.join("Cargo.toml"), "[workspace]\nmembers = [\"crates/lib\"]\n").unwrap();
+        std::fs::write(root.join("delta.toml"), "[git]\nremote_branch = \"origin/dev\"\n").unwrap();
+
+        let metadata = serde_json::to_string(&make_metadata(&[("lib", &[])])).unwrap();
+        let mut host = TestHost::new()
+            .with_commands(vec![Ok(success_output(&metadata))])
+            .with_current_dir(&root.join("crates/lib"));
+        run(
+            &mut host,
+            ["cargo", "delta", "config", "show", "--format", "json"]
+                .iter()
+                .map(ToString::to_string),
+        );
+        let _ = std::fs::remove_dir_all(&root);
+
+        let shown: serde_json::Value = serde_json::from_slice(&host.stdout).unwrap();
+        assert_eq!(shown["git"]["remote_branch"]["value"], "origin/dev");
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)]
     fn run_analyze_cargo_metadata_failure_exits() {
@@ -780,4 +2099,97 @@ mod tests {
 
         let _ = std::fs::remove_dir_all(&tmp);
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn run_subcommand_with_layers_produces_ordered_layers() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_run_layers");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let tree = make_workspace(&[("app", &["app/src/main.rs"], &["lib"]), ("lib", &["lib/src/lib.rs"], &[])]);
+        let json = serde_json::to_string_pretty(&tree).unwrap();
+        let analysis_path = tmp.join("analysis.json");
+        std::fs::write(&analysis_path, &json).unwrap();
+
+        let git_root = tmp.to_string_lossy().to_string();
+        let mut host = TestHost::new().with_commands(vec![
+            Ok(success_output(&format!("{git_root}\n"))),   // git rev-parse
+            Ok(success_output("abc\trefs/heads/master\n")), // git ls-remote
+            Ok(success_output("abc123\n")),                 // git merge-base
+            Ok(success_output("lib/src/lib.rs\n")),         // git diff (one file)
+        ]);
+
+        let analysis = analysis_path.to_string_lossy();
+        run(
+            &mut host,
+            ["cargo", "delta", "run", "--baseline", &analysis, "--current", &analysis, "--layers"]
+                .iter()
+                .map(ToString::to_string),
+        );
+
+        assert!(host.exit_code.is_none());
+        let impact: Impact = serde_json::from_str(&host.stdout_str()).unwrap();
+        assert_eq!(impact.layers, Some(vec![vec!["lib".to_string()], vec!["app".to_string()]]));
+        assert!(host.stderr_str().contains("Layers"));
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn run_subcommand_with_shards_splits_affected() {
+        let tmp = std::env::temp_dir().join("cargo_delta_test_run_shards");
+        let _ = std::fs::create_dir_all(&tmp);
+
+        let tree = make_workspace(&[
+            ("app", &["app/src/main.rs"], &["lib"]),
+            ("cli", &["cli/src/main.rs"], &["lib"]),
+            ("lib", &["lib/src/lib.rs"], &[]),
+        ]);
+        let json = serde_json::to_string_pretty(&tree).unwrap();
+        let analysis_path = tmp.join("analysis.json");
+        let costs_path = tmp.join("costs.json");
+        std::fs::write(&analysis_path, &json).unwrap();
+        std::fs::write(&costs_path, r#"{"app": 5.0, "cli": 4.0, "lib": 1.0}"#).unwrap();
+
+        let git_root = tmp.to_string_lossy().to_string();
+        let mut host = TestHost::new().with_commands(vec![
+            Ok(success_output(&format!("{git_root}\n"))),   // git rev-parse
+            Ok(success_output("abc\trefs/heads/master\n")), // git ls-remote
+            Ok(success_output("abc123\n")),                 // git merge-base
+            Ok(success_output("lib/src/lib.rs\n")),         // git diff (one file)
+        ]);
+
+        let analysis = analysis_path.to_string_lossy();
+        let costs = costs_path.to_string_lossy();
+        run(
+            &mut host,
+            [
+                "cargo",
+                "delta",
+                "run",
+                "--baseline",
+                &analysis,
+                "--current",
+                &analysis,
+                "--shards",
+                "2",
+                "--costs",
+                &costs,
+            ]
+            .iter()
+            .map(ToString::to_string),
+        );
+
+        assert!(host.exit_code.is_none());
+        let impact: Impact = serde_json::from_str(&host.stdout_str()).unwrap();
+        let shards = impact.shards.unwrap();
+        assert_eq!(shards.len(), 2);
+        assert!(shards[0].affected.contains("app"));
+        assert!(shards[1].affected.contains("cli"));
+        assert!(shards[1].affected.contains("lib"));
+        assert!(host.stderr_str().contains("Using costs file"));
+
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
 }
@@ -0,0 +1,429 @@
+use glob::Pattern;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use crate::Impact;
+use crate::crates::Crates;
+use crate::error::{Error, Result};
+use crate::files::FileNode;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Open,
+    Close,
+    Comma,
+    Union,
+    Intersect,
+    Except,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Word(String),
+    Call(String, Vec<Self>),
+    Union(Box<Self>, Box<Self>),
+    Intersect(Box<Self>, Box<Self>),
+    Except(Box<Self>, Box<Self>),
+}
+
+/// Everything a query can be evaluated against.
+pub struct Context<'a> {
+    pub crates: &'a Crates,
+    pub files: &'a FileNode,
+    pub impact: Option<&'a Impact>,
+}
+
+/// Parses and evaluates a query expression, returning the resulting crate names and/or file paths.
+pub fn evaluate(query: &str, context: &Context<'_>) -> Result<BTreeSet<String>> {
+    let tokens = tokenize(query)?;
+    let mut parser = QueryParser { tokens, position: 0 };
+    let expr = parser.parse_expr()?;
+
+    if let Some(token) = parser.peek() {
+        return Err(Error::Query(format!("unexpected {token:?} after end of expression")));
+    }
+
+    context.eval(&expr)
+}
+
+const fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/' | '*' | '?' | ':' | '[' | ']')
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                let _ = chars.next();
+            }
+            // `-` only continues a word, e.g. `my-app`, a leading one is the difference operator.
+            '(' | ')' | ',' | '+' | '^' | '-' => {
+                let _ = chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    ',' => Token::Comma,
+                    '+' => Token::Union,
+                    '-' => Token::Except,
+                    _ => Token::Intersect,
+                });
+            }
+            '"' | '\'' => {
+                let _ = chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(next) if next == c => break,
+                        Some(next) => word.push(next),
+                        None => return Err(Error::Query(format!("unterminated string '{word}'"))),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            c if is_word_char(c) => {
+                let mut word = String::new();
+                while let Some(&next) = chars.peek()
+                    && is_word_char(next)
+                {
+                    word.push(next);
+                    let _ = chars.next();
+                }
+
+                tokens.push(match word.as_str() {
+                    "except" => Token::Except,
+                    "union" => Token::Union,
+                    "intersect" => Token::Intersect,
+                    _ => Token::Word(word),
+                });
+            }
+            _ => return Err(Error::Query(format!("unexpected character '{c}'"))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct QueryParser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl QueryParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<()> {
+        match self.next() {
+            Some(token) if &token == expected => Ok(()),
+            Some(token) => Err(Error::Query(format!("expected {expected:?}, found {token:?}"))),
+            None => Err(Error::Query(format!("expected {expected:?}, found end of expression"))),
+        }
+    }
+
+    // All binary operators share the same precedence and associate to the left.
+    fn parse_expr(&mut self) -> Result<Expr> {
+        let mut left = self.parse_term()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Union) => Expr::Union,
+                Some(Token::Intersect) => Expr::Intersect,
+                Some(Token::Except) => Expr::Except,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            let right = self.parse_term()?;
+            left = op(Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.parse_expr()?;
+                self.expect(&Token::Close)?;
+                Ok(expr)
+            }
+            Some(Token::Word(word)) => {
+                if self.peek() != Some(&Token::Open) {
+                    return Ok(Expr::Word(word));
+                }
+                self.position += 1;
+
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::Close) {
+                    self.position += 1;
+                    return Ok(Expr::Call(word, args));
+                }
+
+                loop {
+                    args.push(self.parse_expr()?);
+                    match self.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::Close) => return Ok(Expr::Call(word, args)),
+                        Some(token) => return Err(Error::Query(format!("expected ',' or ')', found {token:?}"))),
+                        None => return Err(Error::Query("expected ')', found end of expression".to_string())),
+                    }
+                }
+            }
+            Some(token) => Err(Error::Query(format!("unexpected {token:?}"))),
+            None => Err(Error::Query("unexpected end of expression".to_string())),
+        }
+    }
+}
+
+impl Context<'_> {
+    fn eval(&self, expr: &Expr) -> Result<BTreeSet<String>> {
+        match expr {
+            Expr::Word(word) => Ok(BTreeSet::from([word.clone()])),
+            Expr::Union(left, right) => Ok(self.eval(left)?.union(&self.eval(right)?).cloned().collect()),
+            Expr::Intersect(left, right) => Ok(self.eval(left)?.intersection(&self.eval(right)?).cloned().collect()),
+            Expr::Except(left, right) => Ok(self.eval(left)?.difference(&self.eval(right)?).cloned().collect()),
+            Expr::Call(name, args) => self.call(name, args),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[Expr]) -> Result<BTreeSet<String>> {
+        match (name, args) {
+            ("all", []) => Ok(self.crates.get_all_crate_names().into_iter().collect()),
+            ("deps", [arg]) => self.expand(arg, Crates::get_dependencies_transitive),
+            ("rdeps", [arg]) => self.expand(arg, Crates::get_dependents_transitive),
+            ("files", [arg]) => {
+                let mut result = BTreeSet::new();
+                for crate_name in self.eval_crates(arg)? {
+                    result.extend(
+                        self.files
+                            .crate_files(self.crates.dir_for_crate(&crate_name))
+                            .into_iter()
+                            .map(|path| path.to_string_lossy().to_string()),
+                    );
+                }
+                Ok(result)
+            }
+            ("owners", [arg]) => {
+                let mut result = BTreeSet::new();
+                for path in self.eval(arg)? {
+                    let owners = self.files.find_crates_containing_file(&PathBuf::from(path));
+                    result.extend(owners.iter().map(|dir_name| self.crates.crate_for_dir(dir_name)));
+                }
+                Ok(result)
+            }
+            ("kind", [Expr::Word(kind), arg]) => {
+                let pattern = Pattern::new(kind).map_err(|e| Error::Query(format!("invalid kind pattern '{kind}': {e}")))?;
+                let mut result = BTreeSet::new();
+                for crate_name in self.eval_crates(arg)? {
+                    let kinds = self.crates.get_kinds(&crate_name).map(Vec::as_slice).unwrap_or_default();
+                    if kinds.iter().any(|k| pattern.matches(k)) {
+                        let _ = result.insert(crate_name);
+                    }
+                }
+                Ok(result)
+            }
+            ("impacted", [] | [Expr::Word(_)]) => {
+                let impact = self
+                    .impact
+                    .ok_or_else(|| Error::Query("impacted() requires a run result, pass it with --impact".to_string()))?;
+                let tier = match args.first() {
+                    Some(Expr::Word(tier)) => tier.to_lowercase(),
+                    _ => "affected".to_string(),
+                };
+                let crates = match tier.as_str() {
+                    "modified" => &impact.modified,
+                    "affected" => &impact.affected,
+                    "required" => &impact.required,
+                    "roots" => &impact.roots,
+                    _ => return Err(Error::Query(format!("unknown impact tier '{tier}'"))),
+                };
+                Ok(crates.iter().cloned().collect())
+            }
+            _ => Err(Error::Query(format!("unknown function {name}() with {} argument(s)", args.len()))),
+        }
+    }
+
+    fn eval_crates(&self, expr: &Expr) -> Result<BTreeSet<String>> {
+        let names = self.eval(expr)?;
+        if let Some(unknown) = names.iter().find(|name| self.crates.get_dependencies(name).is_none()) {
+            return Err(Error::Query(format!("'{unknown}' is not a workspace crate")));
+        }
+        Ok(names)
+    }
+
+    fn expand(&self, expr: &Expr, related: impl Fn(&Crates, &str) -> Option<Vec<String>>) -> Result<BTreeSet<String>> {
+        let mut result = self.eval_crates(expr)?;
+        for crate_name in result.clone() {
+            result.extend(related(self.crates, &crate_name).unwrap_or_default());
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::FileKind;
+    use std::collections::BTreeMap;
+
+    fn make_crates() -> Crates {
+        // app -> lib -> core, cli -> core
+        serde_json::from_value(serde_json::json!({
+            "crates": { "app": ["lib"], "lib": ["core"], "cli": ["core"], "core": [] },
+            "kinds": { "app": ["bin"], "lib": ["lib"], "cli": ["bin", "lib"], "core": ["lib"] }
+        }))
+        .unwrap()
+    }
+
+    fn make_files() -> FileNode {
+        let mut root = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        for name in ["app", "lib", "cli", "core"] {
+            let mut crate_node = FileNode::new(PathBuf::from(format!("{name}/Cargo.toml")), FileKind::Crate);
+            crate_node.add_child(FileNode::new(PathBuf::from(format!("{name}/src/lib.rs")), FileKind::Target));
+            root.add_child(crate_node);
+        }
+        root
+    }
+
+    fn query(expr: &str) -> Result<Vec<String>> {
+        let crates = make_crates();
+        let files = make_files();
+        let names = |list: &[&str]| list.iter().map(ToString::to_string).collect::<BTreeSet<_>>();
+        let impact = Impact {
+            schema_version: crate::schema::SCHEMA_VERSION,
+            modified: names(&["lib"]),
+            affected: names(&["lib", "app"]),
+            required: names(&["lib", "app", "core"]),
+            roots: names(&["app"]),
+            groups: BTreeSet::new(),
+            features: BTreeMap::new(),
+            layers: None,
+            shards: None,
+        };
+        let context = Context {
+            crates: &crates,
+            files: &files,
+            impact: Some(&impact),
+        };
+        evaluate(expr, &context).map(|set| set.into_iter().collect())
+    }
+
+    #[test]
+    fn deps_includes_argument_and_transitive_dependencies() {
+        assert_eq!(query("deps(app)").unwrap(), vec!["app", "core", "lib"]);
+    }
+
+    #[test]
+    fn rdeps_includes_argument_and_transitive_dependents() {
+        assert_eq!(query("rdeps(core)").unwrap(), vec!["app", "cli", "core", "lib"]);
+    }
+
+    #[test]
+    fn set_operators_combine_left_to_right() {
+        assert_eq!(query("rdeps(core) - deps(app)").unwrap(), vec!["cli"]);
+        assert_eq!(query("deps(app) ^ deps(cli)").unwrap(), vec!["core"]);
+        assert_eq!(query("app + cli except app").unwrap(), vec!["cli"]);
+        assert_eq!(query("app + (cli except app)").unwrap(), vec!["app", "cli"]);
+    }
+
+    #[test]
+    fn leading_hyphen_is_difference() {
+        assert_eq!(query("rdeps(core) -deps(app)").unwrap(), vec!["cli"]);
+        assert_eq!(query("rdeps(core)-deps(app)").unwrap(), vec!["cli"]);
+        assert_eq!(
+            tokenize("a -deps(b)").unwrap(),
+            vec![
+                Token::Word("a".to_string()),
+                Token::Except,
+                Token::Word("deps".to_string()),
+                Token::Open,
+                Token::Word("b".to_string()),
+                Token::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn files_and_owners_round_trip() {
+        assert_eq!(query("files(core)").unwrap(), vec!["core/Cargo.toml", "core/src/lib.rs"]);
+        assert_eq!(query("owners('lib/src/lib.rs')").unwrap(), vec!["lib"]);
+        assert_eq!(query("owners(files(cli))").unwrap(), vec!["cli"]);
+    }
+
+    #[test]
+    fn kind_filters_by_target_kind() {
+        assert_eq!(query("kind(bin, all())").unwrap(), vec!["app", "cli"]);
+        assert_eq!(query("kind(lib, deps(app))").unwrap(), vec!["core", "lib"]);
+    }
+
+    #[test]
+    fn impacted_uses_run_result() {
+        assert_eq!(query("impacted()").unwrap(), vec!["app", "lib"]);
+        assert_eq!(query("impacted(Roots)").unwrap(), vec!["app"]);
+        assert_eq!(query("kind(bin, impacted(required))").unwrap(), vec!["app"]);
+    }
+
+    #[test]
+    fn impacted_without_run_result_fails() {
+        let crates = make_crates();
+        let files = make_files();
+        let context = Context {
+            crates: &crates,
+            files: &files,
+            impact: None,
+        };
+        let err = evaluate("impacted()", &context).unwrap_err();
+        assert!(err.to_string().contains("--impact"));
+    }
+
+    #[test]
+    fn hyphenated_crate_names_are_single_words() {
+        let crates: Crates = serde_json::from_value(serde_json::json!({ "crates": { "my-app": [] } })).unwrap();
+        let files = make_files();
+        let context = Context {
+            crates: &crates,
+            files: &files,
+            impact: None,
+        };
+        let result = evaluate("deps(my-app)", &context).unwrap();
+        assert!(result.contains("my-app"));
+    }
+
+    #[test]
+    fn crates_named_unlike_their_directory_resolve() {
+        let crates: Crates = serde_json::from_value(serde_json::json!({
+            "crates": { "app": ["core-lib"], "core-lib": [] },
+            "dirs": { "app": "app", "core-lib": "core" }
+        }))
+        .unwrap();
+        let files = make_files();
+        let context = Context {
+            crates: &crates,
+            files: &files,
+            impact: None,
+        };
+        let query = |expr: &str| evaluate(expr, &context).unwrap().into_iter().collect::<Vec<_>>();
+
+        assert_eq!(query("files(core-lib)"), vec!["core/Cargo.toml", "core/src/lib.rs"]);
+        assert_eq!(query("owners('core/src/lib.rs')"), vec!["core-lib"]);
+        assert_eq!(query("rdeps(owners('core/src/lib.rs'))"), vec!["app", "core-lib"]);
+    }
+
+    #[test]
+    fn invalid_queries_are_rejected() {
+        assert!(matches!(query("deps(unknown)"), Err(Error::Query(_))));
+        assert!(matches!(query("deps(app"), Err(Error::Query(_))));
+        assert!(matches!(query("deps(app) cli"), Err(Error::Query(_))));
+        assert!(matches!(query("nope(app)"), Err(Error::Query(_))));
+        assert!(matches!(query("'unterminated"), Err(Error::Query(_))));
+        assert!(matches!(query("app & cli"), Err(Error::Query(_))));
+    }
+}
@@ -0,0 +1,272 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::host::Host;
+use crate::utils;
+
+/// Version of the analysis and run result documents written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Version assumed for documents written before `schema_version` existed (cargo-delta 0.2).
+pub const LEGACY_SCHEMA_VERSION: u32 = 0;
+
+pub const fn legacy_schema_version() -> u32 {
+    LEGACY_SCHEMA_VERSION
+}
+
+/// Documents carrying a `schema_version` field.
+pub trait Versioned {
+    fn schema_version_mut(&mut self) -> &mut u32;
+}
+
+/// Loads a versioned JSON document, migrating legacy documents and rejecting unknown versions.
+pub fn load<T: DeserializeOwned + Versioned>(host: &mut impl Host, file_path: &Path) -> Result<T> {
+    load_migrating(host, file_path).map(|(document, _)| document)
+}
+
+/// Like [`load`], also telling whether the document was migrated from the legacy format, so that
+/// fields it lacks can be derived by the caller.
+pub fn load_migrating<T: DeserializeOwned + Versioned>(host: &mut impl Host, file_path: &Path) -> Result<(T, bool)> {
+    let mut document: T = utils::deser_json(file_path)?;
+    let version = document.schema_version_mut();
+    let migrated = *version == LEGACY_SCHEMA_VERSION;
+
+    match *version {
+        SCHEMA_VERSION => {}
+        LEGACY_SCHEMA_VERSION => {
+            let _ = writeln!(
+                host.error(),
+                "Warning: {} has no schema version, migrating from cargo-delta 0.2 format.",
+                file_path.display()
+            );
+            *version = SCHEMA_VERSION;
+        }
+        found => {
+            return Err(Error::SchemaVersion {
+                file: file_path.display().to_string(),
+                found,
+                expected: SCHEMA_VERSION,
+            });
+        }
+    }
+
+    Ok((document, migrated))
+}
+
+fn string_set(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "array",
+        "items": { "type": "string" },
+        "uniqueItems": true
+    })
+}
+
+fn version_property() -> Value {
+    json!({
+        "description": "Version of this document format.",
+        "const": SCHEMA_VERSION
+    })
+}
+
+/// JSON Schema of the document produced by `analyze`.
+pub fn analysis() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "cargo-delta analysis",
+        "type": "object",
+        "required": ["schema_version", "files", "crates"],
+        "properties": {
+            "schema_version": version_property(),
+            "files": { "$ref": "#/$defs/FileNode" },
+            "crates": {
+                "type": "object",
+                "required": ["crates"],
+                "properties": {
+                    "crates": {
+                        "description": "Workspace crates mapped to their workspace dependencies.",
+                        "type": "object",
+                        "additionalProperties": { "type": "array", "items": { "type": "string" } }
+                    },
+                    "kinds": {
+                        "description": "Workspace crates mapped to the kinds of their targets.",
+                        "type": "object",
+                        "additionalProperties": { "type": "array", "items": { "type": "string" } }
+                    },
+                    "features": {
+                        "description": "Workspace crates mapped to their cargo features.",
+                        "type": "object",
+                        "additionalProperties": {
+                            "type": "object",
+                            "additionalProperties": {
+                                "type": "object",
+                                "properties": {
+                                    "features": string_set("Features of the same crate enabled by this feature."),
+                                    "crates": string_set("Optional workspace dependencies enabled by this feature.")
+                                }
+                            }
+                        }
+                    },
+                    "dirs": {
+                        "description": "Workspace crates mapped to the directory name of their manifest.",
+                        "type": "object",
+                        "additionalProperties": { "type": "string" }
+                    }
+                }
+            },
+            "profile": {
+                "description": "The [profile] table of the workspace Cargo.toml.",
+                "type": "object"
+            },
+            "config_files": {
+                "description": "Files of the delta config, relative to the git root.",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "config_metadata": {
+                "description": "The [workspace.metadata.delta] table of the workspace Cargo.toml.",
+                "type": "object"
+            }
+        },
+        "$defs": {
+            "FileNode": {
+                "type": "object",
+                "required": ["path", "kind", "children"],
+                "properties": {
+                    "path": { "type": "string" },
+                    "kind": {
+                        "enum": [
+                            "Workspace",
+                            "Crate",
+                            "Target",
+                            "Module",
+                            "ModulePath",
+                            "MacroInclude",
+                            "FileReference",
+                            "Assume",
+                            "Directory",
+                            "BuildScript",
+                            "BuildInput",
+                            "Mapped",
+                            "Unset"
+                        ]
+                    },
+                    "cfg": {
+                        "description": "cfg predicate of the edge from the parent node.",
+                        "type": "string"
+                    },
+                    "children": { "type": "array", "items": { "$ref": "#/$defs/FileNode" } }
+                }
+            }
+        }
+    })
+}
+
+/// JSON Schema of the document produced by `run`.
+pub fn impact() -> Value {
+    let mut impact_properties = json!({
+        "Modified": string_set("Crates directly modified by Git changes."),
+        "Affected": string_set("Modified crates plus all their dependents, direct and indirect."),
+        "Required": string_set("Affected crates plus all their dependencies, direct and indirect."),
+        "Roots": string_set("Affected crates that no other affected crate depends on."),
+        "Groups": string_set("Configured crate groups with an affected member."),
+        "Features": {
+            "description": "Affected crates mapped to their features whose cfg-gated code or optional dependencies were affected.",
+            "type": "object",
+            "additionalProperties": string_set("Affected features of the crate.")
+        },
+        "Layers": {
+            "description": "Required crates grouped by dependency depth.",
+            "type": "array",
+            "items": { "type": "array", "items": { "type": "string" } }
+        }
+    });
+
+    let mut shard_properties = impact_properties.clone();
+    let _ = shard_properties.as_object_mut().and_then(|properties| properties.remove("Groups"));
+    shard_properties["Weight"] = json!({ "type": "number" });
+
+    impact_properties["schema_version"] = version_property();
+    impact_properties["Shards"] = json!({
+        "description": "Affected crates split into balanced buckets.",
+        "type": "array",
+        "items": {
+            "type": "object",
+            "required": ["Weight", "Modified", "Affected", "Required", "Roots"],
+            "properties": shard_properties
+        }
+    });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "cargo-delta run result",
+        "type": "object",
+        "required": ["schema_version", "Modified", "Affected", "Required", "Roots"],
+        "properties": impact_properties
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHost;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Document {
+        #[serde(default = "legacy_schema_version")]
+        schema_version: u32,
+    }
+
+    impl Versioned for Document {
+        fn schema_version_mut(&mut self) -> &mut u32 {
+            &mut self.schema_version
+        }
+    }
+
+    fn load_str(name: &str, content: &str) -> (TestHost, Result<Document>) {
+        let tmp = std::env::temp_dir().join(name);
+        std::fs::write(&tmp, content).unwrap();
+
+        let mut host = TestHost::new();
+        let result = load(&mut host, &tmp);
+
+        let _ = std::fs::remove_file(&tmp);
+        (host, result)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn load_accepts_current_version() {
+        let (host, result) = load_str("cargo_delta_test_schema_current.json", r#"{"schema_version": 1}"#);
+        assert_eq!(result.unwrap().schema_version, SCHEMA_VERSION);
+        assert!(host.stderr_str().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn load_migrates_legacy_document() {
+        let (host, result) = load_str("cargo_delta_test_schema_legacy.json", "{}");
+        assert_eq!(result.unwrap().schema_version, SCHEMA_VERSION);
+        assert!(host.stderr_str().contains("migrating"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn load_rejects_unknown_version() {
+        let (_, result) = load_str("cargo_delta_test_schema_newer.json", r#"{"schema_version": 99}"#);
+        let err = result.err().unwrap();
+        assert!(matches!(err, Error::SchemaVersion { found: 99, .. }));
+        assert!(err.to_string().contains("version 99"));
+    }
+
+    #[test]
+    fn schemas_require_version() {
+        assert!(analysis()["required"].as_array().unwrap().contains(&json!("schema_version")));
+        assert!(impact()["required"].as_array().unwrap().contains(&json!("schema_version")));
+        assert_eq!(impact()["properties"]["schema_version"]["const"], json!(SCHEMA_VERSION));
+    }
+}
@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::Impact;
+use crate::crates::Crates;
+use crate::files::FileNode;
+
+/// The crate sets of a `run` result, restricted to one bucket of affected crates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Shard {
+    #[serde(rename = "Weight")]
+    pub weight: f64,
+    #[serde(rename = "Modified")]
+    pub modified: BTreeSet<String>,
+    #[serde(rename = "Affected")]
+    pub affected: BTreeSet<String>,
+    #[serde(rename = "Required")]
+    pub required: BTreeSet<String>,
+    #[serde(rename = "Roots")]
+    pub roots: BTreeSet<String>,
+    #[serde(rename = "Features", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, BTreeSet<String>>,
+    #[serde(rename = "Layers", default, skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<Vec<String>>>,
+}
+
+/// Splits the affected crates into `count` buckets of roughly equal weight. Crate weights come
+/// from `costs` and fall back to the number of files a crate owns in the analysis. Every shard
+/// carries its own tiers, so its `Required` set contains all dependencies needed to build it.
+pub fn split(impact: &Impact, crates: &Crates, files: &FileNode, costs: &HashMap<String, f64>, count: usize, layers: bool) -> Vec<Shard> {
+    // The file tree names crates after their manifest directory.
+    let file_counts: HashMap<String, usize> = files
+        .crate_file_counts()
+        .into_iter()
+        .map(|(dir_name, count)| (crates.crate_for_dir(&dir_name), count))
+        .collect();
+
+    let mut weighted: Vec<(&String, f64)> = impact
+        .affected
+        .iter()
+        .map(|name| {
+            let weight = costs.get(name).copied().unwrap_or_else(|| {
+                let file_count = file_counts.get(name).copied().unwrap_or_default();
+                f64::from(u32::try_from(file_count).unwrap_or(u32::MAX))
+            });
+            (name, weight)
+        })
+        .collect();
+
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut buckets: Vec<(f64, BTreeSet<String>)> = vec![(0.0, BTreeSet::new()); count];
+
+    for (name, weight) in weighted {
+        let Some(lightest) = buckets.iter_mut().min_by(|a, b| a.0.total_cmp(&b.0)) else {
+            break;
+        };
+        lightest.0 += weight;
+        let _ = lightest.1.insert(name.clone());
+    }
+
+    buckets
+        .into_iter()
+        .map(|(weight, affected)| {
+            let modified = affected.intersection(&impact.modified).cloned().collect();
+
+            let mut required = affected.clone();
+            for crate_name in &affected {
+                if let Some(transitive_deps) = crates.get_dependencies_transitive(crate_name) {
+                    required.extend(transitive_deps);
+                }
+            }
+
+            let roots = crates.get_roots(&affected);
+            let layers = layers.then(|| crates.get_layers(&required));
+            let features = impact
+                .features
+                .iter()
+                .filter(|(crate_name, _)| affected.contains(*crate_name))
+                .map(|(crate_name, features)| (crate_name.clone(), features.clone()))
+                .collect();
+
+            Shard {
+                weight,
+                modified,
+                affected,
+                required,
+                roots,
+                features,
+                layers,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::FileKind;
+    use crate::schema::SCHEMA_VERSION;
+    use std::path::PathBuf;
+
+    fn names(list: &[&str]) -> BTreeSet<String> {
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    fn make_crates(deps: &[(&str, &[&str])]) -> Crates {
+        let json: HashMap<&str, &[&str]> = deps.iter().copied().collect();
+        serde_json::from_value(serde_json::json!({ "crates": json })).unwrap()
+    }
+
+    fn make_impact(affected: &[&str]) -> Impact {
+        Impact {
+            schema_version: SCHEMA_VERSION,
+            modified: names(affected),
+            affected: names(affected),
+            required: names(affected),
+            roots: names(affected),
+            groups: BTreeSet::new(),
+            features: BTreeMap::new(),
+            layers: None,
+            shards: None,
+        }
+    }
+
+    #[test]
+    fn split_balances_by_cost() {
+        let crates = make_crates(&[("a", &[]), ("b", &[]), ("c", &[]), ("d", &[])]);
+        let files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let costs = HashMap::from([
+            ("a".to_string(), 10.0),
+            ("b".to_string(), 6.0),
+            ("c".to_string(), 4.0),
+            ("d".to_string(), 1.0),
+        ]);
+
+        let shards = split(&make_impact(&["a", "b", "c", "d"]), &crates, &files, &costs, 2, false);
+
+        assert_eq!(shards.len(), 2);
+        assert_eq!(shards[0].affected, names(&["a", "d"]));
+        assert!((shards[0].weight - 11.0).abs() < f64::EPSILON);
+        assert_eq!(shards[1].affected, names(&["b", "c"]));
+        assert!((shards[1].weight - 10.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn split_falls_back_to_file_counts() {
+        let crates = make_crates(&[("big", &[]), ("small", &[])]);
+        let mut files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let mut big = FileNode::new(PathBuf::from("big/Cargo.toml"), FileKind::Crate);
+        big.add_child(FileNode::new(PathBuf::from("big/src/lib.rs"), FileKind::Target));
+        big.add_child(FileNode::new(PathBuf::from("big/src/a.rs"), FileKind::Module));
+        files.add_child(big);
+        files.add_child(FileNode::new(PathBuf::from("small/Cargo.toml"), FileKind::Crate));
+
+        let shards = split(&make_impact(&["big", "small"]), &crates, &files, &HashMap::new(), 2, false);
+
+        assert_eq!(shards[0].affected, names(&["big"]));
+        assert!((shards[0].weight - 3.0).abs() < f64::EPSILON);
+        assert_eq!(shards[1].affected, names(&["small"]));
+    }
+
+    #[test]
+    fn split_counts_files_by_crate_name() {
+        let crates: Crates = serde_json::from_value(serde_json::json!({
+            "crates": { "core-lib": [], "small": [] },
+            "dirs": { "core-lib": "core", "small": "small" }
+        }))
+        .unwrap();
+        let mut files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let mut core = FileNode::new(PathBuf::from("core/Cargo.toml"), FileKind::Crate);
+        core.add_child(FileNode::new(PathBuf::from("core/src/lib.rs"), FileKind::Target));
+        core.add_child(FileNode::new(PathBuf::from("core/src/a.rs"), FileKind::Module));
+        files.add_child(core);
+        files.add_child(FileNode::new(PathBuf::from("small/Cargo.toml"), FileKind::Crate));
+
+        let shards = split(&make_impact(&["core-lib", "small"]), &crates, &files, &HashMap::new(), 2, false);
+
+        assert_eq!(shards[0].affected, names(&["core-lib"]));
+        assert!((shards[0].weight - 3.0).abs() < f64::EPSILON);
+        assert_eq!(shards[1].affected, names(&["small"]));
+        assert!((shards[1].weight - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn split_includes_dependencies_in_required() {
+        let crates = make_crates(&[("app", &["lib"]), ("cli", &["lib"]), ("lib", &[])]);
+        let files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+        let costs = HashMap::from([("app".to_string(), 1.0), ("cli".to_string(), 1.0)]);
+        let impact = Impact {
+            modified: names(&["app"]),
+            ..make_impact(&["app", "cli"])
+        };
+
+        let shards = split(&impact, &crates, &files, &costs, 2, true);
+
+        assert_eq!(shards[0].required, names(&["app", "lib"]));
+        assert_eq!(shards[0].modified, names(&["app"]));
+        assert_eq!(shards[0].layers, Some(vec![vec!["lib".to_string()], vec!["app".to_string()]]));
+        assert_eq!(shards[1].required, names(&["cli", "lib"]));
+        assert!(shards[1].modified.is_empty());
+    }
+
+    #[test]
+    fn shards_serialize_crate_sets_only() {
+        let crates = make_crates(&[("a", &[])]);
+        let files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+
+        let shards = split(&make_impact(&["a"]), &crates, &files, &HashMap::new(), 1, false);
+        let value = serde_json::to_value(&shards[0]).unwrap();
+
+        assert_eq!(value["Affected"], serde_json::json!(["a"]));
+        assert!(value.get("schema_version").is_none());
+        assert!(value.get("Groups").is_none());
+    }
+
+    #[test]
+    fn split_keeps_empty_shards() {
+        let crates = make_crates(&[("a", &[])]);
+        let files = FileNode::new(PathBuf::from("Cargo.toml"), FileKind::Workspace);
+
+        let shards = split(&make_impact(&["a"]), &crates, &files, &HashMap::new(), 3, false);
+
+        assert_eq!(shards.len(), 3);
+        assert!(shards[1].affected.is_empty());
+        assert!(shards[2].affected.is_empty());
+    }
+}
@@ -1,7 +1,8 @@
 use crate::host::Host;
 use std::collections::VecDeque;
+use std::ffi::OsString;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Output;
 
 pub struct TestHost {
@@ -9,6 +10,8 @@ pub struct TestHost {
     pub stderr: Vec<u8>,
     pub exit_code: Option<i32>,
     command_responses: VecDeque<io::Result<Output>>,
+    env_vars: Vec<(OsString, OsString)>,
+    current_dir: Option<PathBuf>,
 }
 
 impl TestHost {
@@ -18,9 +21,25 @@ impl TestHost {
             stderr: Vec::new(),
             exit_code: None,
             command_responses: VecDeque::new(),
+            env_vars: Vec::new(),
+            current_dir: None,
         }
     }
 
+    pub fn with_current_dir(mut self, dir: &Path) -> Self {
+        self.current_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    pub fn with_env_vars(self, vars: &[(&str, &str)]) -> Self {
+        self.with_env_vars_os(vars.iter().map(|(name, value)| ((*name).into(), (*value).into())).collect())
+    }
+
+    pub fn with_env_vars_os(mut self, vars: Vec<(OsString, OsString)>) -> Self {
+        self.env_vars = vars;
+        self
+    }
+
     pub fn with_commands(mut self, responses: Vec<io::Result<Output>>) -> Self {
         self.command_responses = VecDeque::from(responses);
         self
@@ -53,6 +72,16 @@ impl Host for TestHost {
             .pop_front()
             .unwrap_or_else(|| Err(io::Error::other("no more mock command responses")))
     }
+
+    fn env_vars(&mut self) -> Vec<(OsString, OsString)> {
+        self.env_vars.clone()
+    }
+
+    fn current_dir(&mut self) -> io::Result<PathBuf> {
+        self.current_dir
+            .clone()
+            .ok_or_else(|| io::Error::other("no mock current directory"))
+    }
 }
 
 pub fn make_output(code: i32, stdout: &str, stderr: &str) -> Output {
@@ -87,7 +87,13 @@ pub struct UnrelatedFiles {
     pub filtered: Vec<PathBuf>,
 }
 
-pub fn find_unrelated(git_root: &Path, excludes: &[PathBuf], exclude_patterns: &[String], trip_wire_patterns: &[String]) -> UnrelatedFiles {
+pub fn find_unrelated(
+    git_root: &Path,
+    excludes: &[PathBuf],
+    excluded_dirs: &[PathBuf],
+    exclude_patterns: &[String],
+    trip_wire_patterns: &[String],
+) -> UnrelatedFiles {
     fn visit(
         dir: &Path,
         git_root: &Path,
@@ -180,6 +186,11 @@ pub fn find_unrelated(git_root: &Path, excludes: &[PathBuf], exclude_patterns: &
         &compiled_trip_wires,
         &mut result,
     );
+
+    // Files below directories claimed by a crate are accounted for, including new ones.
+    let claimed = |path: &PathBuf| excluded_dirs.iter().any(|dir| path.starts_with(dir));
+    result.unaccounted.retain(|path| !claimed(path));
+    result.trip_wire.retain(|path| !claimed(path));
     result
 }
 
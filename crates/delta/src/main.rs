@@ -3,8 +3,9 @@
 //! A cargo tool to detect impacted crates from git changes.
 
 use cargo_delta_lib::Host;
+use std::ffi::OsString;
 use std::io::{self, Write, stderr, stdout};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 
 /// Default host that runs real OS commands.
@@ -32,6 +33,14 @@ impl Host for RealHost {
         }
         cmd.output()
     }
+
+    fn env_vars(&mut self) -> Vec<(OsString, OsString)> {
+        std::env::vars_os().collect()
+    }
+
+    fn current_dir(&mut self) -> io::Result<PathBuf> {
+        std::env::current_dir()
+    }
 }
 
 fn main() {